@@ -0,0 +1,48 @@
+//! Times parsing a chord-heavy sheet, which is where storing chord keys inline pays off.
+//! Run with `cargo bench --bench chords`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use virtualpiano_rs::parse_sheet;
+
+/// Chords in the generated sheet.
+const CHORDS: usize = 20_000;
+const RUNS: u32 = 20;
+
+/// Two-, three- and four-note chords, a line of sixteen at a time.
+fn chord_heavy_sheet() -> String {
+    let chords = ["[qe]", "[wry]", "[etu]", "[tuos]", "[yi]", "[ups]"];
+    let mut sheet = String::from("#title Chords\n#length 10:00\n");
+    for index in 0..CHORDS {
+        sheet.push_str(chords[index % chords.len()]);
+        sheet.push(if index % 16 == 15 { '\n' } else { ' ' });
+    }
+    sheet
+}
+
+fn main() {
+    let sheet = chord_heavy_sheet();
+    // Once up front, so the first run isn't the only one paying for cold caches.
+    black_box(parse_sheet(&sheet).unwrap());
+
+    let mut fastest = Duration::MAX;
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        black_box(parse_sheet(black_box(&sheet)).unwrap());
+        let elapsed = start.elapsed();
+        fastest = fastest.min(elapsed);
+        total += elapsed;
+    }
+
+    println!(
+        "parse {} chords: {:.2}ms mean, {:.2}ms fastest over {} runs",
+        CHORDS,
+        (total / RUNS).as_secs_f64() * 1000.0,
+        fastest.as_secs_f64() * 1000.0,
+        RUNS
+    );
+}
@@ -0,0 +1,131 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Where playback gets the time from and how it waits, so a performance can be timed against a
+/// fake clock without actually sleeping.
+pub trait Clock: Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+
+    /// Sleeps until `deadline`, as close to it as the clock can manage. Used for the final wait
+    /// before each key event, where oversleeping is audible.
+    fn sleep_until(&self, deadline: Instant) {
+        let now = self.now();
+        if deadline > now {
+            self.sleep(deadline - now);
+        }
+    }
+}
+
+impl<T: Clock + ?Sized> Clock for Box<T> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        (**self).sleep(duration)
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        (**self).sleep_until(deadline)
+    }
+}
+
+/// How long before a deadline [`SystemClock`] stops sleeping and spins instead. OS timers can wake
+/// several milliseconds late, 10-15 on Windows, which smears fast passages.
+const SPIN_TAIL: Duration = Duration::from_millis(1);
+
+/// The real clock, for playing to a keyboard.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let coarse = deadline.saturating_duration_since(Instant::now());
+        if let Some(coarse) = coarse.checked_sub(SPIN_TAIL) {
+            thread::sleep(coarse);
+        }
+        while Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+#[derive(Default)]
+struct FakeTime {
+    elapsed: Duration,
+    sleeps: Vec<Duration>,
+}
+
+/// A clock that only moves when it is slept on, and then instantly. Clones share the same time,
+/// so one can be handed to the player and another kept to inspect afterwards. Tests time
+/// playback with it, and the dry run uses it to print a performance without waiting for it.
+#[derive(Clone)]
+pub struct FakeClock {
+    start: Instant,
+    time: Arc<Mutex<FakeTime>>,
+}
+
+impl FakeClock {
+    pub fn new() -> FakeClock {
+        FakeClock {
+            start: Instant::now(),
+            time: Arc::default(),
+        }
+    }
+
+    /// Total time slept so far.
+    pub fn elapsed(&self) -> Duration {
+        self.time.lock().unwrap().elapsed
+    }
+
+    /// Every sleep requested so far, in order.
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.time.lock().unwrap().sleeps.clone()
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        FakeClock::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut time = self.time.lock().unwrap();
+        time.elapsed += duration;
+        time.sleeps.push(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_clock_wakes_close_to_its_deadlines() {
+        let clock = SystemClock;
+        for millis in [0, 1, 2, 3, 5] {
+            let deadline = Instant::now() + Duration::from_millis(millis);
+            clock.sleep_until(deadline);
+            let late = Instant::now().saturating_duration_since(deadline);
+            // Spinning through the last millisecond keeps it from oversleeping by a timer tick.
+            assert!(late < Duration::from_millis(1), "{:?} late", late);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
+
+use log::warn;
+use rdev::{EventType, Key};
+
+use crate::player::PlayOptions;
+
+/// Keys watched during playback. Defaults to Escape to abort and F9 to toggle pause, with F8
+/// starting and stopping a recording.
+#[derive(Clone, Copy)]
+pub struct Hotkeys {
+    pub abort: Key,
+    pub pause: Key,
+    pub record: Key,
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Hotkeys {
+            abort: Key::Escape,
+            pause: Key::F9,
+            record: Key::F8,
+        }
+    }
+}
+
+/// Drives the abort and pause flags of `options` from a background thread that lives for the
+/// rest of the process.
+pub fn spawn_listener(hotkeys: Hotkeys, options: &PlayOptions) {
+    let abort: Arc<AtomicBool> = options.abort.clone();
+    let paused: Arc<AtomicBool> = options.paused.clone();
+
+    thread::spawn(move || {
+        let result = rdev::listen(move |event| {
+            if let EventType::KeyPress(key) = event.event_type {
+                if key == hotkeys.abort {
+                    abort.store(true, Ordering::Relaxed);
+                } else if key == hotkeys.pause {
+                    paused.fetch_xor(true, Ordering::Relaxed);
+                }
+            }
+        });
+
+        if let Err(err) = result {
+            warn!("Could not listen for playback hotkeys: {:?}", err);
+        }
+    });
+}
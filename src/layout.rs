@@ -0,0 +1,91 @@
+/// VirtualPiano's 61 keys in chromatic order, from C2 up to C7.
+#[rustfmt::skip]
+pub const KEYS: [char; 61] = [
+    '1', '!', '2', '@', '3', '4', '$', '5', '%', '6', '^', '7',
+    '8', '*', '9', '(', '0', 'q', 'Q', 'w', 'W', 'e', 'E', 'r',
+    't', 'T', 'y', 'Y', 'u', 'i', 'I', 'o', 'O', 'p', 'P', 'a',
+    's', 'S', 'd', 'D', 'f', 'g', 'G', 'h', 'H', 'j', 'J', 'k',
+    'l', 'L', 'z', 'Z', 'x', 'c', 'C', 'v', 'V', 'b', 'B', 'n',
+    'm',
+];
+
+/// The keys in `KEYS` that are typed with shift held, each with the key pressed under it.
+#[rustfmt::skip]
+pub const SHIFTED: [(char, char); 25] = [
+    ('!', '1'), ('@', '2'), ('$', '4'), ('%', '5'), ('^', '6'), ('*', '8'), ('(', '9'),
+    ('Q', 'q'), ('W', 'w'), ('E', 'e'), ('T', 't'), ('Y', 'y'), ('I', 'i'), ('O', 'o'),
+    ('P', 'p'), ('S', 's'), ('D', 'd'), ('G', 'g'), ('H', 'h'), ('J', 'j'), ('L', 'l'),
+    ('Z', 'z'), ('C', 'c'), ('V', 'v'), ('B', 'b'),
+];
+
+/// MIDI note number of the first entry in `KEYS`.
+pub const LOWEST_NOTE: u8 = 36;
+
+pub fn index_of(character: char) -> Option<usize> {
+    KEYS.iter().position(|&key| key == character)
+}
+
+/// The key typed under shift for `character`, if VirtualPiano needs shift for it.
+pub fn shift_base(character: char) -> Option<char> {
+    SHIFTED
+        .iter()
+        .find(|&&(shifted, _)| shifted == character)
+        .map(|&(_, base)| base)
+}
+
+pub fn note_to_char(note: u8) -> Option<char> {
+    let index = note.checked_sub(LOWEST_NOTE)?;
+    KEYS.get(index as usize).copied()
+}
+
+pub fn char_to_note(character: char) -> Option<u8> {
+    index_of(character).map(|index| LOWEST_NOTE + index as u8)
+}
+
+/// Shifts `character` by `steps` semitones, clamping at either end of the keyboard. Also returns
+/// whether clamping was needed; characters outside the layout are returned unchanged.
+pub fn transpose(character: char, steps: i32) -> (char, bool) {
+    let Some(index) = index_of(character) else {
+        return (character, false);
+    };
+
+    let target = index as i64 + steps as i64;
+    let clamped = target.clamp(0, KEYS.len() as i64 - 1);
+    (KEYS[clamped as usize], clamped != target)
+}
+
+/// What to do with a note that a transposition moves past either end of the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClampPolicy {
+    /// Leave the note out.
+    Drop,
+    /// Play the key at the nearest end of the keyboard instead.
+    #[default]
+    Nearest,
+    /// Refuse to transpose.
+    Error,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transpose_follows_the_layout() {
+        assert_eq!(transpose('1', 1), ('!', false));
+        assert_eq!(transpose('0', 1), ('q', false));
+        assert_eq!(transpose('q', -2), ('(', false));
+        assert_eq!(transpose('a', 0), ('a', false));
+        // Not a key, so there's nothing to move.
+        assert_eq!(transpose('-', 5), ('-', false));
+    }
+
+    #[test]
+    fn transpose_clamps_at_either_end() {
+        assert_eq!(transpose('n', 2), ('m', true));
+        assert_eq!(transpose('m', 1), ('m', true));
+        assert_eq!(transpose('!', -2), ('1', true));
+        assert_eq!(transpose('5', i32::MIN), ('1', true));
+        assert_eq!(transpose('5', i32::MAX), ('m', true));
+    }
+}
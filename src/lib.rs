@@ -0,0 +1,25 @@
+pub mod clock;
+pub mod hotkeys;
+pub mod layout;
+pub mod output;
+pub mod player;
+pub mod record;
+pub mod sheet;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use clock::{Clock, FakeClock, SystemClock};
+pub use hotkeys::Hotkeys;
+pub use layout::ClampPolicy;
+pub use output::{DryRunOutput, EnigoOutput, KeyOutput};
+#[cfg(feature = "tokio")]
+pub use player::play_sheet_async;
+pub use player::{
+    PlayError, PlayOptions, Player, calibrate, play_sheet, play_sheet_with_clock, wait,
+};
+pub use sheet::{
+    Chord, Header, KeyCheck, PauseDistribution, Position, Sheet, SheetError, SheetStats,
+    SheetWarning, Token, TokenDurations, TokenSchedule, calculate_token_durations,
+    calculate_token_durations_for, parse_header_only, parse_reader, parse_sheet,
+    parse_sheet_checked, parse_sheet_remapped, parse_sheet_with_warnings,
+};
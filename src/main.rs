@@ -1,134 +1,1263 @@
 use std::{
+    collections::{BTreeSet, HashMap, hash_map::DefaultHasher},
     fs,
-    io::{self, Write},
-    path::Path,
-    thread, time,
+    hash::{Hash, Hasher},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{self, ExitCode},
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, SystemTime},
 };
 
-use enigo::{Enigo, Keyboard, Settings};
-use sheet::{Sheet, TokenDurations};
+use clap::Parser;
+use enigo::{Enigo, Key, Settings};
+use fuzzy_matcher::{FuzzyMatcher, skim::SkimMatcherV2};
+use log::{error, info, warn};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
+use rayon::{iter::Either, prelude::*};
+use serde::Deserialize;
+use virtualpiano_rs::{
+    Clock, DryRunOutput, EnigoOutput, FakeClock, Header, Hotkeys, KeyCheck, KeyOutput,
+    PauseDistribution, PlayError, PlayOptions, Player, Sheet, SheetError, SystemClock, Token,
+    calculate_token_durations, calculate_token_durations_for, hotkeys, layout, parse_header_only,
+    parse_sheet_checked, record, sheet,
+    sheet::{SheetCache, SheetParsers},
+    wait,
+};
 
-mod sheet;
+const DEFAULT_CONFIG: &str = "config.toml";
+const DEFAULT_SHEETS_DIR: &str = "./sheets";
+const SHEETS_DIR_VAR: &str = "VIRTUALPIANO_SHEETS";
 
-fn play_sheet(enigo: &mut Enigo, music: Sheet, durations: &TokenDurations) {
-    println!(
-        "Playing '{}' by {}",
-        music.header.title.unwrap_or(String::from("Unknown")),
-        music.header.writer.unwrap_or(String::from("Unknown"))
-    );
-    println!("Starting in 5 seconds...");
-    thread::sleep(time::Duration::from_secs(5));
-
-    for token in music.tokens {
-        match token {
-            sheet::Token::Single(key) => {
-                _ = enigo.key(key, enigo::Direction::Press);
-                thread::sleep(time::Duration::from_secs_f64(durations.single));
-                _ = enigo.key(key, enigo::Direction::Release);
-            }
-            sheet::Token::ShortPause => {
-                thread::sleep(time::Duration::from_secs_f64(durations.short_pause))
-            }
-            sheet::Token::Pause => thread::sleep(time::Duration::from_secs_f64(durations.pause)),
-            sheet::Token::LongPause => {
-                thread::sleep(time::Duration::from_secs_f64(durations.long_pause))
-            }
-            sheet::Token::Many(keys) => {
-                for key in &keys {
-                    _ = enigo.key(*key, enigo::Direction::Press);
-                }
-                thread::sleep(time::Duration::from_secs_f64(durations.single));
-                for key in keys {
-                    _ = enigo.key(key, enigo::Direction::Release);
+/// Parses a command-line length in seconds, such as `2.5`.
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    let secs: f64 = value.parse().map_err(|err| format!("{}", err))?;
+    Duration::try_from_secs_f64(secs).map_err(|_| "must be finite and not negative".to_string())
+}
+
+/// Parses a point in a song as `m:ss`, `h:mm:ss` or seconds, such as `1:30` or `90`.
+fn parse_position(value: &str) -> Result<Duration, String> {
+    let mut secs = 0.0;
+    for part in value.split(':') {
+        let part: f64 = part.parse().map_err(|err| format!("{}", err))?;
+        secs = secs * 60.0 + part;
+    }
+    Duration::try_from_secs_f64(secs).map_err(|_| "must be finite and not negative".to_string())
+}
+
+/// Settings read from `config.toml`. Every key is optional and falls back to the built-in
+/// default.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// Weights of the short, standard and long pauses, rescaled to add up to 1.0.
+    short: f64,
+    standard: f64,
+    long: f64,
+    pause_ratio: f64,
+    many_fast_proportion: f64,
+    many_fast_gap: f64,
+    chord_hold: f64,
+    /// Characters to type in place of sheet characters, for keyboards laid out differently.
+    remap: HashMap<char, char>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            short: 0.2,
+            standard: 0.3,
+            long: 0.5,
+            pause_ratio: 20.0,
+            many_fast_proportion: 0.15,
+            many_fast_gap: 0.0,
+            chord_hold: 1.0,
+            remap: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    fn pause_distribution(&self) -> PauseDistribution {
+        PauseDistribution {
+            short: self.short,
+            standard: self.standard,
+            long: self.long,
+            pause_ratio: self.pause_ratio,
+            many_fast_proportion: self.many_fast_proportion,
+            many_fast_gap: self.many_fast_gap,
+            chord_hold: self.chord_hold,
+        }
+        .normalized()
+    }
+
+    fn key_remap(&self) -> HashMap<char, Key> {
+        self.remap
+            .iter()
+            .map(|(&from, &to)| (from, Key::Unicode(to)))
+            .collect()
+    }
+}
+
+/// Reads the config from `path`, or from `config.toml` if no path was given. A missing
+/// `config.toml` gives the defaults, but a missing file passed with `--config` is an error.
+fn load_config(path: Option<&Path>) -> Result<Config, String> {
+    let config_path = path.unwrap_or(Path::new(DEFAULT_CONFIG));
+    let config = match fs::read_to_string(config_path) {
+        Ok(contents) => toml::from_str(&contents)
+            .map_err(|err| format!("Invalid config '{}': {}", config_path.display(), err))?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound && path.is_none() => Config::default(),
+        Err(err) => {
+            return Err(format!(
+                "Could not read config '{}': {}",
+                config_path.display(),
+                err
+            ));
+        }
+    };
+
+    calculate_token_durations(1.0, &config.pause_distribution())
+        .map_err(|err| format!("Invalid config '{}': {}", config_path.display(), err))?;
+    Ok(config)
+}
+
+#[derive(Parser)]
+#[command(version, about = "Plays VirtualPiano sheets by simulating key presses")]
+struct Args {
+    /// Config file with the pause distribution and key remapping [default: config.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Directory to load sheets from, unless VIRTUALPIANO_SHEETS is set [default: ./sheets]
+    #[arg(long)]
+    dir: Option<PathBuf>,
+
+    /// Play the song with this title and exit
+    #[arg(long)]
+    song: Option<String>,
+
+    /// Playback speed multiplier, e.g. 0.5 for half speed
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+
+    /// Print statistics about the song with this title and exit
+    #[arg(long, value_name = "SONG")]
+    stats: Option<String>,
+
+    /// Only show songs whose writer contains this, ignoring case
+    #[arg(long)]
+    writer: Option<String>,
+
+    /// Check every sheet in this directory for problems and exit
+    #[arg(long, value_name = "DIR")]
+    lint: Option<PathBuf>,
+
+    /// Record what is played on the keyboard into this sheet file, starting and stopping with F8
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Print the available songs and exit
+    #[arg(long)]
+    list: bool,
+
+    /// Read a single sheet from standard input and play it
+    #[arg(long)]
+    stdin: bool,
+
+    /// Randomly vary each duration by up to this fraction, e.g. 0.05 for ±5%
+    #[arg(long, default_value_t = 0.0)]
+    jitter: f64,
+
+    /// Fraction of each note's time to hold its keys: below 1 is staccato, above 1 legato
+    #[arg(long, default_value_t = 1.0)]
+    articulation: f64,
+
+    /// Seed for --jitter and --shuffle, so that a performance can be repeated exactly
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Seconds to wait before playing, or 0 to start immediately
+    #[arg(long, default_value_t = 5)]
+    countdown: u32,
+
+    /// Beats to count in at the song's note tempo after the countdown, to play along with
+    #[arg(long, value_name = "BEATS", default_value_t = 0)]
+    count_in: u32,
+
+    /// Ring the terminal bell on each beat of --count-in
+    #[arg(long, requires = "count_in")]
+    count_in_bell: bool,
+
+    /// Start this far into the song, as m:ss or seconds, e.g. 1:30
+    #[arg(long, value_name = "TIME", value_parser = parse_position)]
+    from: Option<Duration>,
+
+    /// Loop back to --loop-from each time the song reaches this point, until stopped
+    #[arg(long, value_name = "TIME", value_parser = parse_position)]
+    loop_to: Option<Duration>,
+
+    /// Where --loop-to jumps back to [default: the start of the song]
+    #[arg(long, value_name = "TIME", value_parser = parse_position, requires = "loop_to")]
+    loop_from: Option<Duration>,
+
+    /// Seconds of silence between songs when playing them all
+    #[arg(long, default_value = "3", value_parser = parse_seconds)]
+    gap: Duration,
+
+    /// Play all songs in a random order; --seed makes the order repeatable
+    #[arg(long)]
+    shuffle: bool,
+
+    /// Load sheets that aren't valid UTF-8, replacing the invalid bytes
+    #[arg(long)]
+    lossy: bool,
+
+    /// Refuse text sheets with notes on characters that aren't VirtualPiano keys
+    #[arg(long)]
+    strict_keys: bool,
+
+    /// Print the key presses with their timings at once instead of sending them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Parse every sheet afresh instead of reusing the ones cached by earlier runs
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Don't time key presses at startup; playback otherwise sends each one early by that much
+    #[arg(long)]
+    no_calibrate: bool,
+
+    /// Pass `-` as a shorthand for --stdin
+    #[arg(value_parser = ["-"], hide_possible_values = true)]
+    input: Option<String>,
+}
+
+/// Where to load sheets from, and what chose it: `VIRTUALPIANO_SHEETS` if set, then `--dir`, then
+/// `./sheets`.
+fn sheets_dir(dir: Option<&Path>) -> (PathBuf, &'static str) {
+    if let Some(dir) = std::env::var_os(SHEETS_DIR_VAR).filter(|dir| !dir.is_empty()) {
+        return (PathBuf::from(dir), SHEETS_DIR_VAR);
+    }
+    match dir {
+        Some(dir) => (dir.to_path_buf(), "--dir"),
+        None => (PathBuf::from(DEFAULT_SHEETS_DIR), "the default"),
+    }
+}
+
+/// A sheet in the sheets directory. Only its header is read up front, and the rest when it is
+/// first listed in the menu or played, so a large directory loads quickly and a broken sheet
+/// only fails on its own.
+struct Song {
+    /// Location of the sheet relative to the sheets directory.
+    path: PathBuf,
+    file: PathBuf,
+    /// When the file was modified as it was read, for caching the parsed sheet.
+    modified: Option<SystemTime>,
+    header: Header,
+    sheet: OnceLock<Sheet>,
+}
+
+impl Song {
+    /// The whole sheet, reading it with `loader` the first time.
+    fn sheet(&self, loader: &SheetLoader) -> Result<&Sheet, String> {
+        if let Some(sheet) = self.sheet.get() {
+            return Ok(sheet);
+        }
+        let sheet = loader.load(&self.file, &self.path)?;
+        if let (Some(cache), Some(modified)) = (&loader.cache, self.modified)
+            && let Err(err) = cache.insert(&self.file, modified, &sheet)
+        {
+            warn!("Could not cache '{}': {}", self.path.display(), err);
+        }
+        Ok(self.sheet.get_or_init(|| sheet))
+    }
+}
+
+/// Text sheets are read here, so they can be remapped and decoded lossily. Every other format
+/// goes through the registered [`SheetParsers`].
+fn is_text(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "txt")
+}
+
+fn is_sheet_file(path: &Path, parsers: &SheetParsers) -> bool {
+    path.is_file() && parsers.supports(path)
+}
+
+/// Orders songs by title, ignoring case, with untitled songs last. Songs with the same title are
+/// ordered by path, so the menu numbering is the same on every run.
+fn sort_songs(songs: &mut [Song]) {
+    songs.sort_by_cached_key(|song| {
+        let title = song.header.title.as_deref().map(str::to_lowercase);
+        (title.is_none(), title, song.path.clone())
+    });
+}
+
+/// Every sheet file under `sheets_dir`, searching subdirectories too. Returns `None` if
+/// `sheets_dir` itself can't be read.
+fn sheet_files(sheets_dir: &Path, parsers: &SheetParsers) -> Option<Vec<PathBuf>> {
+    let entries = fs::read_dir(sheets_dir).ok()?;
+
+    let mut files = Vec::new();
+    let mut pending = vec![entries];
+    while let Some(entries) = pending.pop() {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                match fs::read_dir(&path) {
+                    Ok(entries) => pending.push(entries),
+                    Err(err) => warn!("Skipping '{}': {}", path.display(), err),
                 }
+            } else if is_sheet_file(&path, parsers) {
+                files.push(path);
             }
-            sheet::Token::ManyFast(keys) => {
-                for key in keys {
-                    _ = enigo.key(key, enigo::Direction::Press);
-                    thread::sleep(time::Duration::from_secs_f64(durations.many_fast));
-                    _ = enigo.key(key, enigo::Direction::Release);
+        }
+    }
+
+    Some(files)
+}
+
+/// How sheet files are read and parsed into songs.
+#[derive(Clone)]
+struct SheetLoader {
+    remap: HashMap<char, Key>,
+    /// Replace invalid UTF-8 with U+FFFD instead of refusing the file.
+    lossy: bool,
+    keys: KeyCheck,
+    parsers: SheetParsers,
+    /// Where parsed sheets are kept between runs, unless `--no-cache` was given.
+    cache: Option<SheetCache>,
+}
+
+impl SheetLoader {
+    /// A directory in `cache` for sheets parsed with these settings, so that changing the
+    /// remapping or upgrading doesn't reuse sheets that were parsed differently.
+    fn settings_cache(&self, cache: &SheetCache) -> SheetCache {
+        let mut remap: Vec<_> = self.remap.iter().collect();
+        remap.sort_by_key(|&(&from, _)| from);
+
+        let mut hasher = DefaultHasher::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        remap.hash(&mut hasher);
+        self.lossy.hash(&mut hasher);
+        (self.keys == KeyCheck::Strict).hash(&mut hasher);
+        SheetCache::new(cache.dir().join(format!("{:016x}", hasher.finish())))
+    }
+
+    /// The song for the sheet at `file`, taken from the cache if the file hasn't changed since it
+    /// was cached, or else with only its header read. See [`SheetLoader::parsed_song`] to read
+    /// the whole sheet.
+    fn song(&self, file: PathBuf, path: PathBuf) -> Result<Song, String> {
+        let modified = fs::metadata(&file)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let cached = self
+            .cache
+            .as_ref()
+            .zip(modified)
+            .and_then(|(cache, modified)| cache.get(&file, modified));
+        let header = match &cached {
+            Some(sheet) => sheet.header.clone(),
+            None => self.load_header(&file, &path)?,
+        };
+
+        Ok(Song {
+            path,
+            file,
+            modified,
+            header,
+            sheet: cached.map(OnceLock::from).unwrap_or_default(),
+        })
+    }
+
+    /// The song for the sheet at `file`, with the whole sheet read, so that a sheet with a mistake
+    /// past its header fails here rather than when it is played.
+    fn parsed_song(&self, file: PathBuf, path: PathBuf) -> Result<Song, String> {
+        let song = self.song(file, path)?;
+        song.sheet(self)?;
+        Ok(song)
+    }
+
+    /// Parses a text sheet, printing any warnings about it under `source`.
+    fn parse(&self, contents: &str, source: &Path) -> Result<Sheet, SheetError> {
+        let (sheet, warnings) = parse_sheet_checked(contents, &self.remap, self.keys)?;
+        for warning in warnings {
+            warn!("'{}': {}", source.display(), warning);
+        }
+        Ok(sheet)
+    }
+
+    /// Reads the text sheet at `path`, reporting problems under `source`.
+    fn read_text(&self, path: &Path, source: &Path) -> Result<String, String> {
+        let bytes = fs::read(path).map_err(|err| err.to_string())?;
+        match String::from_utf8(bytes) {
+            Ok(contents) => Ok(contents),
+            Err(err) if self.lossy => {
+                warn!("'{}': replacing invalid UTF-8", source.display());
+                Ok(String::from_utf8_lossy(err.as_bytes()).into_owned())
+            }
+            Err(_) => Err("not UTF-8 text, pass --lossy to load it anyway".to_string()),
+        }
+    }
+
+    /// Reads and parses the sheet at `path`, reporting problems under `source`. The remapping
+    /// only applies to text sheets.
+    fn load(&self, path: &Path, source: &Path) -> Result<Sheet, String> {
+        if !is_text(path) {
+            return self.parsers.load(path).map_err(|err| err.to_string());
+        }
+
+        let contents = self.read_text(path, source)?;
+        self.parse(&contents, source).map_err(|err| err.to_string())
+    }
+
+    /// Reads just the header of the sheet at `path`, for listing it.
+    fn load_header(&self, path: &Path, source: &Path) -> Result<Header, String> {
+        if !is_text(path) {
+            return self
+                .parsers
+                .load_header(path)
+                .map_err(|err| err.to_string());
+        }
+
+        let contents = self.read_text(path, source)?;
+        parse_header_only(&contents).map_err(|err| err.to_string())
+    }
+}
+
+/// Reads the header of every sheet under `sheets_dir`, spreading the files over a thread pool.
+/// The ones that can't be read are listed together at the end, so one broken file doesn't hide
+/// the others.
+fn load_songs(sheets_dir: &Path, loader: &SheetLoader) -> Option<Vec<Song>> {
+    let (mut songs, mut failed): (Vec<Song>, Vec<_>) = sheet_files(sheets_dir, &loader.parsers)?
+        .into_par_iter()
+        .partition_map(|path| {
+            let relative = path.strip_prefix(sheets_dir).unwrap_or(&path).to_path_buf();
+            match loader.song(path, relative.clone()) {
+                Ok(song) => Either::Left(song),
+                Err(err) => Either::Right((relative, err)),
+            }
+        });
+
+    if !failed.is_empty() {
+        failed.sort();
+        warn!("{} sheets could not be loaded:", failed.len());
+        for (path, err) in &failed {
+            warn!("  '{}': {}", path.display(), err);
+        }
+    }
+
+    sort_songs(&mut songs);
+    Some(songs)
+}
+
+struct SheetWatcher {
+    /// Canonical sheets directory, which event paths are reported relative to.
+    root: PathBuf,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    /// Loads changed sheets the same way as when they were first loaded.
+    loader: SheetLoader,
+    _watcher: RecommendedWatcher,
+}
+
+fn watch_songs(sheets_dir: &Path, loader: &SheetLoader) -> Option<SheetWatcher> {
+    let start = || -> notify::Result<SheetWatcher> {
+        let root = fs::canonicalize(sheets_dir)?;
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+        Ok(SheetWatcher {
+            root,
+            events,
+            loader: loader.clone(),
+            _watcher: watcher,
+        })
+    };
+
+    match start() {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            warn!("Sheets will not be reloaded on change: {}", err);
+            None
+        }
+    }
+}
+
+/// Applies any changes seen by `watcher` to `songs`. Changed sheets are read in full, and one
+/// that no longer parses keeps its previously loaded version.
+fn refresh_songs(songs: &mut Vec<Song>, watcher: &SheetWatcher) {
+    let mut changed = BTreeSet::new();
+    // Sheets are read when they are first played, which shows up as access events.
+    for event in watcher.events.try_iter().flatten() {
+        if !event.kind.is_access() {
+            changed.extend(event.paths);
+        }
+    }
+
+    for path in changed {
+        let Ok(relative) = path.strip_prefix(&watcher.root) else {
+            continue;
+        };
+
+        if !path.exists() {
+            songs.retain(|song| {
+                let removed = song.path.starts_with(relative);
+                if removed {
+                    println!("Removed '{}'.", song.path.display());
                 }
+                !removed
+            });
+            continue;
+        }
+        if !is_sheet_file(&path, &watcher.loader.parsers) {
+            continue;
+        }
+
+        let existing = songs.iter().position(|song| song.path == relative);
+        match (
+            watcher
+                .loader
+                .parsed_song(path.clone(), relative.to_path_buf()),
+            existing,
+        ) {
+            (Ok(song), Some(index)) => {
+                songs[index] = song;
+                println!("Reloaded '{}'.", relative.display());
+            }
+            (Ok(song), None) => {
+                songs.push(song);
+                println!("Added '{}'.", relative.display());
+            }
+            (Err(err), Some(_)) => error!(
+                "Could not reload '{}', keeping the previous version: {}",
+                relative.display(),
+                err
+            ),
+            (Err(err), None) => {
+                error!("Could not load '{}': {}", relative.display(), err)
             }
         }
     }
+    sort_songs(songs);
+}
+
+fn describe(header: &Header) -> String {
+    format!(
+        "'{}' by {}",
+        header.title.as_deref().unwrap_or("Unknown"),
+        header.writer.as_deref().unwrap_or("Unknown")
+    )
+}
+
+fn describe_song(song: &Song) -> String {
+    format!("{} ({})", describe(&song.header), song.path.display())
+}
+
+/// Formats seconds as `m:ss`, rounded to the nearest second.
+fn format_length(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
 }
 
-fn main() {
-    let pause_distribution = sheet::PauseDistribution {
-        short: 0.2,
-        standard: 0.3,
-        long: 0.5,
-        pause_ratio: 20.0,
-        many_fast_proportion: 0.15,
+fn find_song<'a>(songs: &'a [Song], title: &str) -> Option<&'a Song> {
+    songs.iter().find(|song| {
+        song.header
+            .title
+            .as_deref()
+            .is_some_and(|song_title| song_title.eq_ignore_ascii_case(title))
+    })
+}
+
+/// Indices of the songs whose title best matches `query`. More than one means the query was
+/// ambiguous.
+fn search_songs(songs: &[&Song], query: &str) -> Vec<usize> {
+    let matcher = SkimMatcherV2::default().ignore_case();
+    let scores: Vec<(usize, i64)> = songs
+        .iter()
+        .enumerate()
+        .filter_map(|(index, song)| {
+            let title = song.header.title.as_deref()?;
+            Some((index, matcher.fuzzy_match(title, query)?))
+        })
+        .collect();
+
+    let Some(best) = scores.iter().map(|&(_, score)| score).max() else {
+        return Vec::new();
     };
+    scores
+        .into_iter()
+        .filter(|&(_, score)| score == best)
+        .map(|(index, _)| index)
+        .collect()
+}
 
-    let sheets_dir = Path::new("./sheets"); // Path to your sheets directory
+/// Whether the song's writer contains `writer`, ignoring case.
+fn matches_writer(song: &Song, writer: &str) -> bool {
+    song.header
+        .writer
+        .as_deref()
+        .is_some_and(|song_writer| song_writer.to_lowercase().contains(&writer.to_lowercase()))
+}
 
-    let mut songs = Vec::new();
-    if let Ok(entries) = fs::read_dir(sheets_dir) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                if path.is_file() {
-                    let file_contents = fs::read_to_string(path).unwrap();
+/// How far the estimated playing time may differ from `#length` before lint warns about it.
+const LENGTH_TOLERANCE: f64 = 0.2;
 
-                    songs.push(sheet::parse_sheet(file_contents.as_str()).unwrap());
+/// How long lint expects a note to be in a sheet without `#bpm`, about 200 notes a minute.
+const NOMINAL_NOTE_LENGTH: Duration = Duration::from_millis(300);
+
+/// Problems that don't stop a sheet from playing but probably aren't intended. The text parser
+/// already reports unknown keys with their positions, so `check_keys` is only needed for sheets
+/// in other formats.
+fn lint_warnings(sheet: &Sheet, distribution: &PauseDistribution, check_keys: bool) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    let mut unmapped: Vec<String> = Vec::new();
+    for key in sheet
+        .tokens
+        .iter()
+        .flat_map(Token::keys)
+        .filter(|_| check_keys)
+    {
+        let name = match key {
+            Key::Unicode(character) if layout::index_of(*character).is_some() => continue,
+            Key::Unicode(character) => format!("'{}'", character),
+            other => format!("{:?}", other),
+        };
+        if !unmapped.contains(&name) {
+            unmapped.push(name);
+        }
+    }
+    if !unmapped.is_empty() {
+        warnings.push(format!(
+            "notes map to keys outside the VirtualPiano layout: {}",
+            unmapped.join(", ")
+        ));
+    }
+
+    // Without `#bpm` the durations are solved to fill `#length` exactly, so the sheet is timed at a
+    // typical tempo instead, which doesn't depend on it.
+    let nominal = calculate_token_durations_for(sheet, distribution).and_then(|durations| {
+        if sheet.header.bpm.is_some() {
+            return Ok(durations);
+        }
+        // Notes grow in proportion to the multiplier, so the unit one gives the scale.
+        let unit = calculate_token_durations(1.0, distribution)?;
+        let multiplier = NOMINAL_NOTE_LENGTH.as_secs_f64() / unit.single.as_secs_f64();
+        match multiplier.is_finite() {
+            true => calculate_token_durations(multiplier, distribution),
+            // Only fast arpeggios take any time, and there is no tempo to compare against.
+            false => Ok(durations),
+        }
+    });
+    match nominal {
+        Ok(durations) => {
+            let declared = sheet.header.length;
+            let estimated = sheet.estimated_duration(&durations).as_secs_f64();
+            if declared > 0.0 && ((estimated - declared) / declared).abs() > LENGTH_TOLERANCE {
+                warnings.push(format!(
+                    "declared length of {:.0}s differs from the estimated {:.0}s by more than {}%",
+                    declared,
+                    estimated,
+                    LENGTH_TOLERANCE * 100.0
+                ));
+            }
+        }
+        Err(err) => warnings.push(err.to_string()),
+    }
+
+    warnings
+}
+
+/// Parses every sheet under `sheets_dir` and prints what is wrong with each. Returns `false` if
+/// any sheet could not be read or parsed.
+fn lint(sheets_dir: &Path, distribution: &PauseDistribution, keys: KeyCheck) -> bool {
+    let parsers = SheetParsers::default();
+    let Some(mut files) = sheet_files(sheets_dir, &parsers) else {
+        error!(
+            "Could not read the sheets directory '{}'.",
+            sheets_dir.display()
+        );
+        return false;
+    };
+    files.sort();
+
+    // Sheets are checked as written, without the config's remapping, since the warnings are
+    // about the VirtualPiano layout.
+    let (mut errors, mut warnings) = (0, 0);
+    for path in &files {
+        let parsed = match is_text(path) {
+            true => fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|contents| {
+                    parse_sheet_checked(&contents, &HashMap::new(), keys)
+                        .map_err(|err| err.to_string())
+                }),
+            false => parsers
+                .load(path)
+                .map(|sheet| (sheet, Vec::new()))
+                .map_err(|err| err.to_string()),
+        };
+        match parsed {
+            Ok((sheet, sheet_warnings)) => {
+                let sheet_warnings = sheet_warnings.iter().map(ToString::to_string);
+                let lint_warnings = lint_warnings(&sheet, distribution, !is_text(path));
+                for warning in sheet_warnings.chain(lint_warnings) {
+                    println!("{}: warning: {}", path.display(), warning);
+                    warnings += 1;
                 }
             }
+            Err(err) => {
+                println!("{}: error: {}", path.display(), err);
+                errors += 1;
+            }
         }
-    } else {
-        eprintln!("Error: Could not read the 'sheets' directory.");
-        return;
     }
 
-    let mut enigo = Enigo::new(&Settings::default()).unwrap();
+    println!(
+        "Checked {} sheets: {} errors, {} warnings.",
+        files.len(),
+        errors,
+        warnings
+    );
+    errors == 0
+}
+
+fn print_stats(song: &Sheet, distribution: &PauseDistribution) -> Result<(), SheetError> {
+    let stats = song.stats(&calculate_token_durations_for(song, distribution)?);
+
+    println!("{}", describe(&song.header));
+    println!("Single notes:     {}", stats.singles);
+    println!("Chords:           {}", stats.chords);
+    println!("Fast arpeggios:   {}", stats.fast_arpeggios);
+    println!("Short pauses:     {}", stats.short_pauses);
+    println!("Pauses:           {}", stats.pauses);
+    println!("Long pauses:      {}", stats.long_pauses);
+    println!("Rests:            {}", stats.rests);
+    println!("Declared length:  {}", format_length(stats.declared_length));
+    println!(
+        "Estimated length: {}",
+        format_length(stats.estimated_length.as_secs_f64())
+    );
+    if let Some((character, uses)) = stats.most_used {
+        println!("Most used key:    '{}' ({} times)", character, uses);
+    }
+    Ok(())
+}
+
+/// The player every song is played through, sharing one output and set of options.
+type SongPlayer = Player<Box<dyn KeyOutput>, Box<dyn Clock>>;
+
+/// Set while a song is playing, so Ctrl-C waits for it to let go of its keys before exiting.
+static PLAYING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The exit status of a program stopped by Ctrl-C.
+const INTERRUPTED_STATUS: i32 = 130;
+
+/// Makes Ctrl-C stop the song that is playing, as the abort hotkey does, and exit once its keys
+/// are released. Outside playback it exits straight away.
+fn handle_ctrl_c(options: &PlayOptions) {
+    let abort = options.abort.clone();
+    let installed = ctrlc::set_handler(move || {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        // Playback clears the flag when it starts, so keep setting it until the song has stopped.
+        while PLAYING.load(Ordering::SeqCst) {
+            abort.store(true, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+        }
+        process::exit(INTERRUPTED_STATUS);
+    });
+    if let Err(err) = installed {
+        warn!("Ctrl-C may leave keys held down: {}", err);
+    }
+}
+
+/// Plays `song`, returning whether it played without an error.
+fn play(player: &mut SongPlayer, song: &Sheet) -> bool {
+    info!("Selected {}", describe(&song.header));
+    println!("Press Escape to stop playback, F9 to pause or resume.");
+    PLAYING.store(true, Ordering::SeqCst);
+    let result = player.play(song);
+    PLAYING.store(false, Ordering::SeqCst);
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        process::exit(INTERRUPTED_STATUS);
+    }
+    match result {
+        Ok(()) => return true,
+        Err(PlayError::Sheet(err)) => warn!("Skipping {}: {}", describe(&song.header), err),
+        Err(err) => error!("{}", err),
+    }
+    false
+}
+
+/// How "Play all" goes through the songs.
+struct Playlist {
+    /// Silence between songs.
+    gap: Duration,
+    /// Plays the songs in a random order, seeded by `PlayOptions::seed` when set.
+    shuffle: bool,
+}
+
+/// Plays every song once with a gap in between. The abort hotkey skips the current song, and
+/// pressing it again before the next one starts stops the playlist.
+fn play_all(songs: &[&Song], loader: &SheetLoader, player: &mut SongPlayer, playlist: &Playlist) {
+    let mut order = songs.to_vec();
+    if playlist.shuffle {
+        let mut rng = match player.options.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        order.shuffle(&mut rng);
+    }
+
+    for (index, song) in order.into_iter().enumerate() {
+        if index > 0 {
+            player.options.abort.store(false, Ordering::Relaxed);
+            println!(
+                "Next song in {} seconds, press Escape to stop.",
+                playlist.gap.as_secs_f64()
+            );
+            if !wait(playlist.gap, &player.options) {
+                println!("Playlist stopped.");
+                return;
+            }
+        }
+
+        println!(
+            "\nNow playing {}/{}: {}",
+            index + 1,
+            songs.len(),
+            describe_song(song)
+        );
+        match song.sheet(loader) {
+            Ok(sheet) => _ = play(player, sheet),
+            Err(err) => warn!("Skipping {}: {}", describe_song(song), err),
+        }
+    }
+}
 
+fn run_menu(
+    songs: &mut Vec<Song>,
+    loader: &SheetLoader,
+    watcher: Option<&SheetWatcher>,
+    player: &mut SongPlayer,
+    playlist: &Playlist,
+    mut writer: Option<String>,
+) {
     loop {
+        if let Some(watcher) = watcher {
+            refresh_songs(songs, watcher);
+        }
+
         println!("\nSong Selection Menu:");
         if songs.is_empty() {
-            println!("No songs found in the 'sheets' directory.");
+            println!("No songs found in the sheets directory.");
             break;
         }
-        for (i, song) in songs.iter().enumerate() {
+        let shown: Vec<&Song> = songs
+            .iter()
+            .filter(|song| {
+                writer
+                    .as_deref()
+                    .is_none_or(|writer| matches_writer(song, writer))
+            })
+            .collect();
+        if let Some(writer) = &writer {
+            println!(
+                "Showing {} of {} songs by writers matching '{}'.",
+                shown.len(),
+                songs.len(),
+                writer
+            );
+        }
+        // Only the headers have been read, so the menu shows the declared length. A sheet's notes
+        // are parsed once it is chosen.
+        for (i, song) in shown.iter().enumerate() {
             println!(
-                "{}. '{}' by {}",
+                "{}. {} - {}",
                 i + 1,
-                song.header.title.clone().unwrap_or("Unknown".to_string()),
-                song.header.writer.clone().unwrap_or("Unknown".to_string())
+                describe_song(song),
+                format_length(song.header.length)
             );
         }
-        println!("{}. Exit", songs.len() + 1);
+        println!("{}. Play all", shown.len() + 1);
+        println!("{}. Filter by writer", shown.len() + 2);
+        println!("{}. Exit", shown.len() + 3);
 
-        print!("Enter your choice: ");
+        print!("Enter a number or part of a title: ");
         io::stdout().flush().unwrap();
 
         let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+        // Standard input was closed, so no further choice can be made.
+        if io::stdin().read_line(&mut input).unwrap() == 0 {
+            break;
+        }
+
+        if input.trim().is_empty() {
+            println!("Invalid input. Please enter a number or part of a title.");
+            continue;
+        }
 
+        // Anything that isn't a number is looked up as part of a title.
         let choice: usize = match input.trim().parse() {
             Ok(num) => num,
-            Err(_) => {
-                println!("Invalid input. Please enter a number.");
-                continue;
-            }
+            Err(_) => match search_songs(&shown, input.trim())[..] {
+                [] => {
+                    println!("No song title matches '{}'.", input.trim());
+                    continue;
+                }
+                [index] => index + 1,
+                ref candidates => {
+                    println!("'{}' matches several songs:", input.trim());
+                    for &index in candidates {
+                        println!("{}. {}", index + 1, describe_song(shown[index]));
+                    }
+                    continue;
+                }
+            },
         };
 
-        if choice == songs.len() + 1 {
+        if choice == shown.len() + 1 {
+            play_all(&shown, loader, player, playlist);
+            continue;
+        }
+        if choice == shown.len() + 2 {
+            print!("Enter part of a writer's name, or nothing to show every song: ");
+            io::stdout().flush().unwrap();
+            let mut input = String::new();
+            io::stdin().read_line(&mut input).unwrap();
+            writer = Some(input.trim().to_string()).filter(|writer| !writer.is_empty());
+            continue;
+        }
+        if choice == shown.len() + 3 {
             break;
         }
 
-        if choice > 0 && choice <= songs.len() {
-            let song = songs[choice - 1].clone();
-            let durations = sheet::calculate_token_durations(
-                song.header.length / song.tokens.iter().count() as f64,
-                &pause_distribution,
-            )
-            .unwrap();
-
-            println!("{:#?}", durations);
-            play_sheet(&mut enigo, song, &durations);
+        if choice > 0 && choice <= shown.len() {
+            let song = shown[choice - 1];
+            match song.sheet(loader) {
+                Ok(sheet) => _ = play(player, sheet),
+                Err(err) => error!("Could not load {}: {}", describe_song(song), err),
+            }
         } else {
             println!("Invalid choice. Please try again.");
         }
     }
 }
+
+fn record_sheet(path: &Path) -> Result<(), String> {
+    let hotkeys = Hotkeys::default();
+    println!("Press {:?} to start recording.", hotkeys.record);
+    let sheet = record::record(hotkeys.record).map_err(|err| err.to_string())?;
+    if sheet.note_count() == 0 {
+        return Err("Nothing was recorded.".to_string());
+    }
+
+    fs::write(path, sheet::to_string(&sheet))
+        .map_err(|err| format!("Could not write '{}': {}", path.display(), err))?;
+    println!(
+        "Saved {} notes over {:.1} seconds to '{}'.",
+        sheet.note_count(),
+        sheet.header.length,
+        path.display()
+    );
+    Ok(())
+}
+
+fn read_stdin_sheet(loader: &SheetLoader) -> Result<Sheet, String> {
+    let mut input = String::new();
+    io::stdin()
+        .read_to_string(&mut input)
+        .map_err(|err| format!("Could not read standard input: {}", err))?;
+    if input.trim().is_empty() {
+        return Err("No sheet was provided on standard input.".to_string());
+    }
+
+    loader
+        .parse(&input, Path::new("<stdin>"))
+        .map_err(|err| err.to_string())
+}
+
+/// The keyboard to play to and the clock to time it by. The dry run plays on a fake clock, which
+/// prints the whole performance at once with the times it would have had.
+fn create_output(dry_run: bool) -> (Box<dyn KeyOutput>, Box<dyn Clock>) {
+    if dry_run {
+        let clock = FakeClock::new();
+        (
+            Box::new(DryRunOutput::with_clock(clock.clone())),
+            Box::new(clock),
+        )
+    } else {
+        let enigo = Enigo::new(&Settings::default()).unwrap();
+        (Box::new(EnigoOutput::new(enigo)), Box::new(SystemClock))
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    // Warnings and errors show by default; `RUST_LOG=debug` adds every key event with its timing.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let config = match load_config(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let distribution = config.pause_distribution();
+    let mut loader = SheetLoader {
+        remap: config.key_remap(),
+        lossy: args.lossy,
+        keys: match args.strict_keys {
+            true => KeyCheck::Strict,
+            false => KeyCheck::Lenient,
+        },
+        parsers: SheetParsers::default(),
+        cache: None,
+    };
+    if !args.no_cache {
+        loader.cache = SheetCache::user().map(|cache| loader.settings_cache(&cache));
+    }
+
+    let new_player = || {
+        let (output, clock) = create_output(args.dry_run);
+        let mut player = Player::new(output, config.pause_distribution())
+            .options(PlayOptions {
+                seed: args.seed,
+                articulation: args.articulation,
+                start_at: args.from.unwrap_or_default(),
+                loop_from: args.loop_from.unwrap_or_default(),
+                loop_to: args.loop_to,
+                count_in_bell: args.count_in_bell,
+                // The dry run prints a line per key event, which would break up the bar.
+                progress: !args.dry_run,
+                ..PlayOptions::default()
+            })
+            .speed(args.speed)
+            .jitter(args.jitter)
+            .countdown(args.countdown)
+            .count_in(args.count_in)
+            .clock(clock);
+        // The dry run prints key events instead of sending them, so there is nothing to time.
+        if !args.no_calibrate && !args.dry_run {
+            match player.calibrate() {
+                Ok(latency) => info!(
+                    "Key events take {:.2}ms to send",
+                    latency.as_secs_f64() * 1000.0
+                ),
+                Err(err) => warn!("Could not time key events: {}", err),
+            }
+        }
+        hotkeys::spawn_listener(Hotkeys::default(), &player.options);
+        handle_ctrl_c(&player.options);
+        player
+    };
+
+    if let Some(dir) = &args.lint {
+        return match lint(dir, &distribution, loader.keys) {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        };
+    }
+
+    if let Some(path) = &args.record {
+        if let Err(err) = record_sheet(path) {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if args.stdin || args.input.is_some() {
+        let song = match read_stdin_sheet(&loader) {
+            Ok(song) => song,
+            Err(err) => {
+                error!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        };
+
+        return match play(&mut new_player(), &song) {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        };
+    }
+
+    let (dir, source) = sheets_dir(args.dir.as_deref());
+    if !dir.is_dir() {
+        error!(
+            "The sheets directory '{}' (from {}) does not exist.",
+            dir.display(),
+            source
+        );
+        return ExitCode::FAILURE;
+    }
+    let Some(mut songs) = load_songs(&dir, &loader) else {
+        error!("Could not read the sheets directory '{}'.", dir.display());
+        return ExitCode::FAILURE;
+    };
+    info!("Loaded {} songs from '{}'", songs.len(), dir.display());
+
+    if args.list {
+        for song in &songs {
+            if args
+                .writer
+                .as_deref()
+                .is_none_or(|writer| matches_writer(song, writer))
+            {
+                println!("{}", describe_song(song));
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if let Some(name) = &args.stats {
+        let Some(song) = find_song(&songs, name) else {
+            error!("No song titled '{}'.", name);
+            return ExitCode::FAILURE;
+        };
+        let printed = song
+            .sheet(&loader)
+            .and_then(|sheet| print_stats(sheet, &distribution).map_err(|err| err.to_string()));
+        if let Err(err) = printed {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let selected = match &args.song {
+        None => None,
+        Some(name) => match find_song(&songs, name).map(|song| song.sheet(&loader)) {
+            Some(Ok(sheet)) => Some(sheet),
+            Some(Err(err)) => {
+                error!("Could not load '{}': {}", name, err);
+                return ExitCode::FAILURE;
+            }
+            None => {
+                error!("No song titled '{}'.", name);
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    let mut player = new_player();
+    match selected {
+        Some(song) if !play(&mut player, song) => return ExitCode::FAILURE,
+        Some(_) => {}
+        None => {
+            let watcher = watch_songs(&dir, &loader);
+            run_menu(
+                &mut songs,
+                &loader,
+                watcher.as_ref(),
+                &mut player,
+                &Playlist {
+                    gap: args.gap,
+                    shuffle: args.shuffle,
+                },
+                args.writer.clone(),
+            );
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// A fresh directory under the system's temporary one, holding `files`.
+    fn sheets_dir(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("virtualpiano-rs-{}-{}", name, process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    fn loader() -> SheetLoader {
+        SheetLoader {
+            remap: HashMap::new(),
+            lossy: false,
+            keys: KeyCheck::Lenient,
+            parsers: SheetParsers::default(),
+            cache: None,
+        }
+    }
+
+    fn titles(songs: &[Song]) -> Vec<&str> {
+        songs
+            .iter()
+            .map(|song| song.header.title.as_deref().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn broken_sheets_leave_the_others_loaded() {
+        let dir = sheets_dir(
+            "broken",
+            &[
+                ("good.txt", b"#title Good\n#length 1\na s"),
+                ("no-length.txt", b"#title No length\na s"),
+                ("bad-notes.txt", b"#title Bad notes\n#length 1\na [s"),
+            ],
+        );
+        let loader = loader();
+        let songs = load_songs(&dir, &loader).unwrap();
+
+        // Only the header is read up front, so a mistake in the notes shows once it's played.
+        assert_eq!(titles(&songs), ["Bad notes", "Good"]);
+        assert!(songs[0].sheet(&loader).is_err());
+        assert!(songs[1].sheet(&loader).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_utf8_is_skipped_unless_lossy() {
+        let dir = sheets_dir(
+            "utf8",
+            &[
+                ("good.txt", b"#title Good\n#length 1\na s"),
+                ("latin1.txt", b"#title Caf\xe9\n#length 1\nd f"),
+            ],
+        );
+        assert_eq!(titles(&load_songs(&dir, &loader()).unwrap()), ["Good"]);
+
+        let lossy = SheetLoader {
+            lossy: true,
+            ..loader()
+        };
+        assert_eq!(
+            titles(&load_songs(&dir, &lossy).unwrap()),
+            ["Caf\u{FFFD}", "Good"]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn lint_warns_about_a_doubtful_length() {
+        let warns = |input: &str| {
+            let sheet = virtualpiano_rs::parse_sheet(input).unwrap();
+            lint_warnings(&sheet, &Config::default().pause_distribution(), false)
+                .iter()
+                .any(|warning| warning.starts_with("declared length"))
+        };
+        assert!(!warns("#length 1.2\nasdf"));
+        assert!(warns("#length 1:00\nasdf"));
+        // `#bpm` sets the tempo, so it is `#beats` that gives a doubtful length.
+        assert!(!warns("#bpm 60\n#beats 4\nasdf"));
+        assert!(warns("#bpm 60\n#beats 8\nasdf"));
+    }
+}
@@ -0,0 +1,128 @@
+use std::{collections::HashMap, time::Instant};
+
+use enigo::{Direction, Enigo, InputError, Key, Keyboard};
+
+use crate::clock::{Clock, FakeClock};
+
+pub trait KeyOutput {
+    fn press(&mut self, key: Key) -> Result<(), InputError>;
+    fn release(&mut self, key: Key) -> Result<(), InputError>;
+}
+
+impl<T: KeyOutput + ?Sized> KeyOutput for Box<T> {
+    fn press(&mut self, key: Key) -> Result<(), InputError> {
+        (**self).press(key)
+    }
+
+    fn release(&mut self, key: Key) -> Result<(), InputError> {
+        (**self).release(key)
+    }
+}
+
+pub struct EnigoOutput {
+    enigo: Enigo,
+}
+
+impl EnigoOutput {
+    pub fn new(enigo: Enigo) -> EnigoOutput {
+        EnigoOutput { enigo }
+    }
+}
+
+impl KeyOutput for EnigoOutput {
+    fn press(&mut self, key: Key) -> Result<(), InputError> {
+        self.enigo.key(key, Direction::Press)
+    }
+
+    fn release(&mut self, key: Key) -> Result<(), InputError> {
+        self.enigo.key(key, Direction::Release)
+    }
+}
+
+/// Prints every key event with its time since the first one instead of pressing anything, e.g.
+/// `[00:01.250] press 'a'`.
+#[derive(Default)]
+pub struct DryRunOutput {
+    start: Option<Instant>,
+    held: HashMap<Key, Instant>,
+    clock: Option<FakeClock>,
+}
+
+impl DryRunOutput {
+    pub fn new() -> DryRunOutput {
+        DryRunOutput::default()
+    }
+
+    /// An output that times its events with `clock` rather than the real time. Playing to it on
+    /// the same clock prints the whole performance straight away, at the times it would have had.
+    pub fn with_clock(clock: FakeClock) -> DryRunOutput {
+        DryRunOutput {
+            clock: Some(clock),
+            ..DryRunOutput::default()
+        }
+    }
+
+    fn now(&self) -> Instant {
+        match &self.clock {
+            Some(clock) => clock.now(),
+            None => Instant::now(),
+        }
+    }
+
+    fn timestamp(&mut self, now: Instant) -> String {
+        let elapsed = now - *self.start.get_or_insert(now);
+        let millis = elapsed.as_millis();
+        format!(
+            "[{:02}:{:02}.{:03}]",
+            millis / 60_000,
+            millis / 1000 % 60,
+            millis % 1000
+        )
+    }
+}
+
+fn key_name(key: Key) -> String {
+    match key {
+        Key::Unicode(character) => format!("'{}'", character),
+        other => format!("{:?}", other),
+    }
+}
+
+impl KeyOutput for DryRunOutput {
+    fn press(&mut self, key: Key) -> Result<(), InputError> {
+        let now = self.now();
+        println!("{} press {}", self.timestamp(now), key_name(key));
+        self.held.insert(key, now);
+        Ok(())
+    }
+
+    fn release(&mut self, key: Key) -> Result<(), InputError> {
+        let now = self.now();
+        let timestamp = self.timestamp(now);
+        match self.held.remove(&key) {
+            Some(pressed) => println!(
+                "{} release {} after {}ms",
+                timestamp,
+                key_name(key),
+                (now - pressed).as_millis()
+            ),
+            None => println!("{} release {}", timestamp, key_name(key)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn dry_run_times_events_by_its_clock() {
+        let clock = FakeClock::new();
+        let mut output = DryRunOutput::with_clock(clock.clone());
+        assert_eq!(output.timestamp(output.now()), "[00:00.000]");
+        clock.sleep(Duration::from_millis(61_250));
+        assert_eq!(output.timestamp(output.now()), "[01:01.250]");
+    }
+}
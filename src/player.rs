@@ -0,0 +1,1684 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread, time,
+};
+
+use enigo::{Direction, InputError, Key};
+use log::debug;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+#[cfg(feature = "tokio")]
+mod asynchronous;
+
+#[cfg(feature = "tokio")]
+pub use asynchronous::play_sheet_async;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    layout,
+    output::KeyOutput,
+    sheet::{
+        PauseDistribution, Sheet, SheetError, Token, TokenDurations, calculate_token_durations_for,
+    },
+};
+
+#[derive(Debug)]
+pub enum PlayError {
+    InvalidSpeed(f64),
+    InvalidJitter(f64),
+    InvalidSwing(f64),
+    InvalidArticulation(f64),
+    /// `loop_to` is not after `loop_from`.
+    InvalidLoop,
+    /// `loop_from` is set without a `loop_to` for the loop to end at.
+    LoopWithoutEnd,
+    Sheet(SheetError),
+    Input {
+        key: Key,
+        direction: Direction,
+        source: InputError,
+    },
+}
+
+impl fmt::Display for PlayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayError::InvalidSpeed(speed) => write!(
+                f,
+                "Playback speed must be between {} and {}, got {}",
+                MIN_SPEED, MAX_SPEED, speed
+            ),
+            PlayError::InvalidJitter(jitter) => {
+                write!(
+                    f,
+                    "Timing jitter must be between 0.0 and 1.0, got {}",
+                    jitter
+                )
+            }
+            PlayError::InvalidSwing(swing) => {
+                write!(f, "Swing must be between 0.0 and 1.0, got {}", swing)
+            }
+            PlayError::InvalidArticulation(articulation) => write!(
+                f,
+                "Articulation must be greater than zero and at most {}, got {}",
+                MAX_ARTICULATION, articulation
+            ),
+            PlayError::InvalidLoop => write!(f, "The loop must end after it starts"),
+            PlayError::LoopWithoutEnd => write!(f, "A loop start needs an end to loop back from"),
+            PlayError::Sheet(err) => write!(f, "{}", err),
+            PlayError::Input {
+                key,
+                direction,
+                source,
+            } => {
+                let action = match direction {
+                    Direction::Press => "press",
+                    Direction::Release => "release",
+                    Direction::Click => "click",
+                };
+                write!(f, "Could not {} {:?}: {}", action, key, source)
+            }
+        }
+    }
+}
+
+impl Error for PlayError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PlayError::Input { source, .. } => Some(source),
+            PlayError::Sheet(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+pub struct PlayOptions {
+    /// Number of times to play the sheet back to back; `0` and `1` both play it once.
+    pub repeat: u32,
+    /// Tempo multiplier applied to every duration, e.g. `0.5` for half speed.
+    pub speed: f64,
+    /// Randomly stretches or shrinks each duration by up to this fraction, e.g. `0.05` for ±5%.
+    pub jitter: f64,
+    /// Seed for the jitter, so a performance can be reproduced exactly. Random when unset.
+    pub seed: Option<u64>,
+    /// Lengthens on-beat notes and shortens the off-beat note after them by the same amount.
+    /// `0.0` is straight time, and around `0.66` gives a triplet (2:1) shuffle.
+    pub swing: f64,
+    /// Fraction of each note's time that its keys stay down. Below `1.0` leaves a gap before the
+    /// next note (staccato), and above it holds into the next note (legato).
+    pub articulation: f64,
+    /// Seconds to count down before the first note, giving time to focus the piano window.
+    pub countdown: u32,
+    /// Beats counted in after the countdown, a note's length apart, so the song starts on the
+    /// beat after the last. `0` starts right away.
+    pub count_in: u32,
+    /// Sounds the terminal bell on each beat of the count-in as well as printing it.
+    pub count_in_bell: bool,
+    /// How far into the performance to start, measured at normal speed. Tokens that start before
+    /// it are skipped, and sustains that are down at that point are pressed when playback starts.
+    pub start_at: time::Duration,
+    /// Where the A-B loop jumps back to, measured like `start_at`. Only used with `loop_to`.
+    pub loop_from: time::Duration,
+    /// When set, playback jumps back to `loop_from` every time it gets this far, until aborted.
+    pub loop_to: Option<time::Duration>,
+    /// How long sending one key event takes, as measured by [`calibrate`]. Every event is sent
+    /// this much early so that it lands on time.
+    pub latency: time::Duration,
+    /// Sounds the terminal bell on every beat while playing.
+    pub metronome: bool,
+    /// Draws a progress bar with the elapsed and total time on the current terminal line.
+    pub progress: bool,
+    /// Set to stop playback as soon as possible; held keys are released first.
+    pub abort: Arc<AtomicBool>,
+    /// While set, playback stops advancing; keys that are already down stay held.
+    pub paused: Arc<AtomicBool>,
+}
+
+impl Default for PlayOptions {
+    fn default() -> Self {
+        PlayOptions {
+            repeat: 1,
+            speed: 1.0,
+            jitter: 0.0,
+            seed: None,
+            swing: 0.0,
+            articulation: 1.0,
+            countdown: 5,
+            count_in: 0,
+            count_in_bell: false,
+            start_at: time::Duration::ZERO,
+            loop_from: time::Duration::ZERO,
+            loop_to: None,
+            latency: time::Duration::ZERO,
+            metronome: false,
+            progress: false,
+            abort: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+/// Longest stretch slept without checking the abort and pause flags, which bounds how long an
+/// abort takes to stop playback.
+const ABORT_POLL_INTERVAL: time::Duration = time::Duration::from_millis(20);
+
+/// Sleeps for `duration`, waking periodically to check the abort flag. Time spent paused does not
+/// count towards `duration`. Returns `false` if playback was aborted.
+pub fn wait(duration: time::Duration, options: &PlayOptions) -> bool {
+    wait_on(&SystemClock, duration, options)
+}
+
+fn wait_on(clock: &dyn Clock, duration: time::Duration, options: &PlayOptions) -> bool {
+    // Sleeping towards a deadline keeps oversleeping in one slice from adding up over the wait.
+    let mut deadline = clock.now() + duration;
+    loop {
+        if options.abort.load(Ordering::Relaxed) {
+            return false;
+        }
+        if options.paused.load(Ordering::Relaxed) {
+            let paused_at = clock.now();
+            clock.sleep(ABORT_POLL_INTERVAL);
+            deadline += clock.now() - paused_at;
+            continue;
+        }
+
+        let now = clock.now();
+        if now >= deadline {
+            return true;
+        }
+        clock.sleep((deadline - now).min(ABORT_POLL_INTERVAL));
+    }
+}
+
+/// Wall-clock anchor for a performance. Events are waited for at absolute offsets from `base`, so
+/// time spent sending keys is absorbed instead of adding up over a song.
+struct Schedule<'a> {
+    clock: &'a dyn Clock,
+    base: time::Instant,
+}
+
+impl Schedule<'_> {
+    fn start(clock: &dyn Clock) -> Schedule<'_> {
+        Schedule {
+            clock,
+            base: clock.now(),
+        }
+    }
+
+    /// Time since the start, not counting time spent paused.
+    fn elapsed(&self) -> time::Duration {
+        self.clock.now() - self.base
+    }
+
+    /// Sleeps until `at` after the start, waking periodically to check the abort flag.
+    /// Time spent paused pushes the whole schedule back. Returns `false` if playback was aborted.
+    fn wait_until(&mut self, at: time::Duration, options: &PlayOptions) -> bool {
+        loop {
+            if options.abort.load(Ordering::Relaxed) {
+                return false;
+            }
+            if options.paused.load(Ordering::Relaxed) {
+                let paused_at = self.clock.now();
+                self.clock.sleep(ABORT_POLL_INTERVAL);
+                self.base += self.clock.now() - paused_at;
+                continue;
+            }
+
+            let now = self.clock.now();
+            let target = self.base + at;
+            if now >= target {
+                return true;
+            }
+            // Coarse sleeps stop a whole slice short of the target, so one that oversleeps by up
+            // to a slice still leaves the last stretch to the precise `sleep_until`.
+            match target - now > ABORT_POLL_INTERVAL * 2 {
+                true => self.clock.sleep(ABORT_POLL_INTERVAL),
+                false => self.clock.sleep_until(target),
+            }
+        }
+    }
+}
+
+/// Perturbs `duration` by up to `jitter` of itself in either direction, never going below zero.
+fn humanize(duration: time::Duration, jitter: f64, rng: &mut StdRng) -> time::Duration {
+    if jitter == 0.0 {
+        return duration;
+    }
+
+    duration.mul_f64((1.0 + rng.random_range(-jitter..=jitter)).max(0.0))
+}
+
+/// Rings the terminal bell every `interval`, on a fixed schedule so the clicks don't drift,
+/// until `finished` or the abort flag is set. Beats are skipped while playback is paused.
+fn run_metronome(
+    clock: &dyn Clock,
+    interval: time::Duration,
+    options: &PlayOptions,
+    finished: &AtomicBool,
+) {
+    let mut next = clock.now();
+    while !finished.load(Ordering::Relaxed) && !options.abort.load(Ordering::Relaxed) {
+        if options.paused.load(Ordering::Relaxed) {
+            clock.sleep(ABORT_POLL_INTERVAL);
+            next = clock.now();
+            continue;
+        }
+
+        let now = clock.now();
+        if now >= next {
+            print!("\x07");
+            _ = io::stdout().flush();
+            next += interval;
+        } else {
+            clock.sleep((next - now).min(ABORT_POLL_INTERVAL));
+        }
+    }
+}
+
+const PROGRESS_WIDTH: usize = 30;
+
+fn format_time(duration: time::Duration) -> String {
+    let secs = duration.as_secs_f64().round() as u64;
+    format!("{:02}:{:02}", secs / 60, secs % 60)
+}
+
+/// A single-line progress bar, redrawn in place with a carriage return after every event.
+struct Progress {
+    title: String,
+    total: time::Duration,
+    elapsed: time::Duration,
+    visible: bool,
+}
+
+impl Progress {
+    fn update(&mut self, elapsed: time::Duration) {
+        self.elapsed = elapsed;
+        if !self.visible {
+            return;
+        }
+
+        let fraction = match self.total.is_zero() {
+            false => self.elapsed.div_duration_f64(self.total).clamp(0.0, 1.0),
+            true => 1.0,
+        };
+        let filled = (fraction * PROGRESS_WIDTH as f64).round() as usize;
+        print!(
+            "\r{} [{}{}] {} / {}",
+            self.title,
+            "#".repeat(filled),
+            "-".repeat(PROGRESS_WIDTH - filled),
+            format_time(self.elapsed),
+            format_time(self.total)
+        );
+        _ = io::stdout().flush();
+    }
+
+    /// Moves past the bar so that later output starts on a fresh line.
+    fn finish(&self) {
+        if self.visible {
+            println!();
+        }
+    }
+}
+
+/// Prints `Starting in 3... 2... 1...` a second at a time. Returns `false` if playback was aborted.
+fn count_down(clock: &dyn Clock, options: &PlayOptions) -> bool {
+    if options.countdown == 0 {
+        return true;
+    }
+
+    print!("Starting in");
+    for remaining in (1..=options.countdown).rev() {
+        print!(" {}...", remaining);
+        _ = io::stdout().flush();
+        if !wait_on(clock, time::Duration::from_secs(1), options) {
+            println!();
+            return false;
+        }
+    }
+    println!();
+    true
+}
+
+/// Prints `Counting in 1 2 3 4` a beat apart, on a fixed schedule like the metronome's, and waits
+/// out the last beat. Returns `false` if playback was aborted.
+fn count_in(clock: &dyn Clock, beat: time::Duration, options: &PlayOptions) -> bool {
+    if options.count_in == 0 || beat.is_zero() {
+        return true;
+    }
+
+    let mut schedule = Schedule::start(clock);
+    print!("Counting in");
+    for count in 0..options.count_in {
+        if !schedule.wait_until(beat * count, options) {
+            println!();
+            return false;
+        }
+        print!(" {}", count + 1);
+        if options.count_in_bell {
+            print!("\x07");
+        }
+        _ = io::stdout().flush();
+    }
+    let completed = schedule.wait_until(beat * options.count_in, options);
+    println!();
+    completed
+}
+
+/// Key events sent by [`calibrate`], half of them presses and half releases.
+const CALIBRATION_EVENTS: u32 = 20;
+
+/// Measures how long `output` takes to send a key event, by pressing and releasing Shift a few
+/// times, which types nothing. The result is meant for [`PlayOptions::latency`].
+pub fn calibrate(output: &mut impl KeyOutput) -> Result<time::Duration, PlayError> {
+    let mut keys = PressedKeys::new(output);
+    let start = time::Instant::now();
+    for _ in 0..CALIBRATION_EVENTS / 2 {
+        keys.send(Key::Shift, Direction::Press)?;
+        keys.send(Key::Shift, Direction::Release)?;
+    }
+    Ok(start.elapsed() / CALIBRATION_EVENTS)
+}
+
+fn send(output: &mut impl KeyOutput, key: Key, direction: Direction) -> Result<(), PlayError> {
+    let sent = match direction {
+        Direction::Release => output.release(key),
+        _ => output.press(key),
+    };
+    sent.map_err(|source| PlayError::Input {
+        key,
+        direction,
+        source,
+    })
+}
+
+/// Presses `key`. Keys that VirtualPiano types with shift get an explicit shift around the key
+/// under it, since `Key::Unicode` doesn't add shift reliably on every platform.
+fn press(output: &mut impl KeyOutput, key: Key) -> Result<(), PlayError> {
+    let Some(base) = shift_base(key) else {
+        return send(output, key, Direction::Press);
+    };
+
+    send(output, Key::Shift, Direction::Press)?;
+    let pressed = send(output, Key::Unicode(base), Direction::Press);
+    send(output, Key::Shift, Direction::Release)?;
+    pressed
+}
+
+/// Releases `key`, or the key under it if it was pressed with shift.
+fn release(output: &mut impl KeyOutput, key: Key) -> Result<(), PlayError> {
+    let key = shift_base(key).map_or(key, Key::Unicode);
+    send(output, key, Direction::Release)
+}
+
+fn shift_base(key: Key) -> Option<char> {
+    match key {
+        Key::Unicode(character) => layout::shift_base(character),
+        _ => None,
+    }
+}
+
+/// The keys a performance holds down. Whatever is still down when it's dropped is let go, so a
+/// panic or an early return can't leave a key stuck down on the desktop.
+struct PressedKeys<'a, O: KeyOutput> {
+    output: &'a mut O,
+    down: Vec<Key>,
+}
+
+impl<'a, O: KeyOutput> PressedKeys<'a, O> {
+    fn new(output: &'a mut O) -> PressedKeys<'a, O> {
+        PressedKeys {
+            output,
+            down: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, key: Key, direction: Direction) -> Result<(), PlayError> {
+        match direction {
+            Direction::Release => {
+                release(self.output, key)?;
+                if let Some(index) = self.down.iter().position(|down| *down == key) {
+                    self.down.remove(index);
+                }
+            }
+            _ => {
+                press(self.output, key)?;
+                self.down.push(key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets go of every key still down, returning the first failure instead of ignoring it as
+    /// dropping does.
+    fn release_all(mut self) -> Result<(), PlayError> {
+        let mut result = Ok(());
+        for key in std::mem::take(&mut self.down) {
+            if let Err(err) = release(self.output, key) {
+                result = result.and(Err(err));
+            }
+        }
+
+        result
+    }
+}
+
+impl<O: KeyOutput> Drop for PressedKeys<'_, O> {
+    fn drop(&mut self) {
+        for key in std::mem::take(&mut self.down) {
+            let _ = release(self.output, key);
+        }
+    }
+}
+
+/// A key going down or up `at` into the performance.
+struct Event {
+    at: time::Duration,
+    direction: Direction,
+    key: Key,
+}
+
+/// Every key event of a performance in time order. `end` is when the last token, including any
+/// trailing pause, finishes.
+struct Timeline {
+    events: Vec<Event>,
+    end: time::Duration,
+}
+
+/// Adds a press of `key` at `at`. A legato note of the same key that is still down then is let go
+/// at the same instant, so the key retriggers instead of being cut short by the late release.
+fn push_press(events: &mut Vec<Event>, key: Key, at: time::Duration) {
+    if let Some(last) = events.iter_mut().rev().find(|event| event.key == key)
+        && matches!(last.direction, Direction::Release)
+        && last.at > at
+    {
+        last.at = at;
+    }
+
+    events.push(Event {
+        at,
+        direction: Direction::Press,
+        key,
+    });
+}
+
+/// Adds a key that goes down at `start` and comes back up `hold` later.
+fn push_note(events: &mut Vec<Event>, key: Key, start: time::Duration, hold: time::Duration) {
+    push_press(events, key, start);
+    events.push(Event {
+        at: start + hold,
+        direction: Direction::Release,
+        key,
+    });
+}
+
+/// Whether swing lengthens or shortens `token`: single notes, including ties and sustains.
+fn swings(token: &Token) -> bool {
+    matches!(token, Token::Single(..) | Token::Tied(..) | Token::Hold(_))
+}
+
+/// Lays out `sheet` as timed press and release events, `options.repeat` times with a long pause in
+/// between, keeping only the tokens that start within `section` of the performance. Each token
+/// takes the time [`Sheet::schedule`] gives it, and swing, jitter and articulation are applied on
+/// top, so playback only has to send each event at its time. Notes of different tokens may
+/// overlap, as with a `Hold` that stays down under later notes; events at the same instant keep
+/// the order they were generated in, so a release always comes before the next press of the same
+/// key.
+fn build_timeline(
+    sheet: &Sheet,
+    durations: &TokenDurations,
+    options: &PlayOptions,
+    rng: &mut StdRng,
+    section: (time::Duration, Option<time::Duration>),
+) -> Timeline {
+    let mut events = Vec::new();
+    let mut time = time::Duration::ZERO;
+    let start = section.0.div_f64(options.speed);
+    let end = section.1.map(|end| end.div_f64(options.speed));
+    let mut started = start.is_zero();
+    // Presses the sustains that are down once the skipped tokens are behind.
+    let mut resume = |events: &mut Vec<Event>, time, held: &[Key], tied: &[Key]| {
+        if !started && time >= start {
+            started = true;
+            for key in held.iter().chain(tied) {
+                push_press(events, *key, start);
+            }
+        }
+        started
+    };
+
+    'passes: for iteration in 0..options.repeat.max(1) {
+        if iteration > 0 {
+            time += humanize(durations.long_pause, options.jitter, rng);
+        }
+
+        let mut held = Vec::new();
+        // Keys kept down by a `_` tie until the note it ties into ends.
+        let mut tied: Vec<Key> = Vec::new();
+        // Notes are swung in pairs: what the on-beat note gains, the off-beat one after it loses.
+        // Pauses in between don't break a pair up.
+        let mut on_beat = true;
+        let mut swung = time::Duration::ZERO;
+        let mut schedule = sheet.schedule(durations);
+        while let Some((_, token, mut length)) = schedule.next() {
+            let mut jittered = |duration: time::Duration| humanize(duration, options.jitter, rng);
+
+            if swings(token) {
+                if on_beat {
+                    // Never more than the off-beat note has to give.
+                    let next = schedule
+                        .clone()
+                        .find(|(_, token, _)| swings(token))
+                        .map_or(time::Duration::ZERO, |(_, _, next)| next);
+                    swung = length.min(next).mul_f64(options.swing / 2.0);
+                    length += swung;
+                } else {
+                    length -= swung;
+                }
+                on_beat = !on_beat;
+            }
+
+            if let Some(end) = end
+                && time >= end
+            {
+                // Sustains are cut off with the section.
+                if resume(&mut events, time, &held, &tied) {
+                    for key in held.into_iter().chain(tied) {
+                        events.push(Event {
+                            at: end,
+                            direction: Direction::Release,
+                            key,
+                        });
+                    }
+                }
+                break 'passes;
+            }
+
+            // Tokens before `start` are still laid out, to keep the timing of the rest, but their
+            // events are thrown away.
+            let skipped = match resume(&mut events, time, &held, &tied) {
+                true => None,
+                false => Some(events.len()),
+            };
+
+            match token {
+                Token::TempoStart(_) | Token::TempoEnd => continue,
+                Token::ReleaseHold(key) => {
+                    if let Some(index) = held.iter().position(|held| held == key) {
+                        held.remove(index);
+                        if skipped.is_none() {
+                            events.push(Event {
+                                at: time,
+                                direction: Direction::Release,
+                                key: *key,
+                            });
+                        }
+                    }
+                    continue;
+                }
+                Token::Hold(key) => {
+                    if let Some(index) = tied.iter().position(|tied| tied == key) {
+                        tied.remove(index);
+                    } else {
+                        push_press(&mut events, *key, time);
+                    }
+                    held.push(*key);
+                    time += jittered(length);
+                }
+                Token::Single(key, _) => {
+                    let allotted = jittered(length);
+                    let hold = allotted.mul_f64(options.articulation);
+                    if let Some(index) = tied.iter().position(|tied| tied == key) {
+                        tied.remove(index);
+                        events.push(Event {
+                            at: time + hold,
+                            direction: Direction::Release,
+                            key: *key,
+                        });
+                    } else {
+                        push_note(&mut events, *key, time, hold);
+                    }
+                    time += allotted;
+                }
+                Token::Tied(key, _) => {
+                    if !tied.contains(key) {
+                        push_press(&mut events, *key, time);
+                        tied.push(*key);
+                    }
+                    time += jittered(length);
+                }
+                Token::ShortPause
+                | Token::ShortPauses(_)
+                | Token::Pause
+                | Token::LongPause
+                | Token::Rest(_) => time += jittered(length),
+                Token::Many(keys, _) => {
+                    let allotted = jittered(length);
+                    let hold = allotted.mul_f64(durations.chord_hold * options.articulation);
+                    for key in keys {
+                        push_press(&mut events, *key, time);
+                    }
+                    for key in keys {
+                        events.push(Event {
+                            at: time + hold,
+                            direction: Direction::Release,
+                            key: *key,
+                        });
+                    }
+                    time += allotted;
+                }
+                Token::ManyFast(keys) => {
+                    // The notes and the gaps between them share out the arpeggio's time as they
+                    // do at the nominal tempo.
+                    let nominal = token.duration(durations);
+                    let tempo = match nominal.is_zero() {
+                        true => 1.0,
+                        false => length.div_duration_f64(nominal),
+                    };
+                    for (note, key) in keys.iter().enumerate() {
+                        if note > 0 {
+                            time += jittered(durations.many_fast_gap.mul_f64(tempo));
+                        }
+                        let allotted = jittered(durations.many_fast.mul_f64(tempo));
+                        push_note(
+                            &mut events,
+                            *key,
+                            time,
+                            allotted.mul_f64(options.articulation),
+                        );
+                        time += allotted;
+                    }
+                }
+            }
+            if let Some(skipped) = skipped {
+                events.truncate(skipped);
+            }
+        }
+
+        // Sustains that are still down at the end of a pass are let go there.
+        if resume(&mut events, time, &held, &tied) {
+            for key in held.into_iter().chain(tied) {
+                events.push(Event {
+                    at: time,
+                    direction: Direction::Release,
+                    key,
+                });
+            }
+        }
+    }
+
+    // Notes that run past the end of the section are let go there, and fast arpeggios that it
+    // cuts through lose their remaining notes.
+    if let Some(end) = end {
+        events.retain(|event| event.at < end || matches!(event.direction, Direction::Release));
+        for event in &mut events {
+            event.at = event.at.min(end);
+        }
+        time = time.min(end);
+    }
+
+    // A stable sort, so simultaneous events stay in the order above.
+    events.sort_by_key(|event| event.at);
+    for event in &mut events {
+        event.at = event.at.saturating_sub(start);
+    }
+    Timeline {
+        events,
+        end: time.saturating_sub(start),
+    }
+}
+
+/// Sends every event of `timeline` at its time. Returns `false` if playback was aborted. Keys that
+/// are still down when playback stops, for whatever reason, are released.
+fn play_timeline(
+    clock: &dyn Clock,
+    output: &mut impl KeyOutput,
+    timeline: &Timeline,
+    options: &PlayOptions,
+    progress: &mut Progress,
+) -> Result<bool, PlayError> {
+    let mut schedule = Schedule::start(clock);
+    let mut keys = PressedKeys::new(output);
+    let mut result = Ok(true);
+    for event in &timeline.events {
+        if !schedule.wait_until(event.at.saturating_sub(options.latency), options) {
+            result = Ok(false);
+            break;
+        }
+
+        debug!(
+            "{:?} {:?} at {:.3}s, {:.1}ms late",
+            event.direction,
+            event.key,
+            event.at.as_secs_f64(),
+            (schedule.elapsed().as_secs_f64() - event.at.as_secs_f64()) * 1000.0
+        );
+        if let Err(err) = keys.send(event.key, event.direction) {
+            result = Err(err);
+            break;
+        }
+        progress.update(event.at);
+    }
+
+    if let Ok(true) = result {
+        result = Ok(schedule.wait_until(timeline.end, options));
+        progress.update(timeline.end);
+    }
+
+    let released = keys.release_all();
+    result.and_then(|completed| released.map(|()| completed))
+}
+
+/// The slowest and fastest playback speeds. Much slower would overflow the durations, and much
+/// faster plays a whole song in an instant.
+const MIN_SPEED: f64 = 0.01;
+const MAX_SPEED: f64 = 100.0;
+
+/// The longest a note's keys can be held, as a multiple of its time.
+const MAX_ARTICULATION: f64 = 10.0;
+
+/// Checks the options and returns the random number generator for the jitter.
+fn prepare(options: &PlayOptions) -> Result<StdRng, PlayError> {
+    if !(MIN_SPEED..=MAX_SPEED).contains(&options.speed) {
+        return Err(PlayError::InvalidSpeed(options.speed));
+    }
+    if !(0.0..=1.0).contains(&options.jitter) {
+        return Err(PlayError::InvalidJitter(options.jitter));
+    }
+    if !(0.0..=1.0).contains(&options.swing) {
+        return Err(PlayError::InvalidSwing(options.swing));
+    }
+    if options.articulation <= 0.0 || options.articulation > MAX_ARTICULATION {
+        return Err(PlayError::InvalidArticulation(options.articulation));
+    }
+    if options
+        .loop_to
+        .is_some_and(|loop_to| loop_to <= options.loop_from)
+    {
+        return Err(PlayError::InvalidLoop);
+    }
+    if options.loop_to.is_none() && !options.loop_from.is_zero() {
+        return Err(PlayError::LoopWithoutEnd);
+    }
+
+    Ok(match options.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_os_rng(),
+    })
+}
+
+pub fn play_sheet(
+    output: &mut impl KeyOutput,
+    music: &Sheet,
+    durations: &TokenDurations,
+    options: &PlayOptions,
+) -> Result<(), PlayError> {
+    play_sheet_with_clock(&SystemClock, output, music, durations, options)
+}
+
+/// Plays `music` like [`play_sheet`], taking the time from `clock` and waiting on it.
+pub fn play_sheet_with_clock(
+    clock: &impl Clock,
+    output: &mut impl KeyOutput,
+    music: &Sheet,
+    durations: &TokenDurations,
+    options: &PlayOptions,
+) -> Result<(), PlayError> {
+    let mut rng = prepare(options)?;
+    let durations = &durations.scaled(options.speed);
+
+    let title = music
+        .header
+        .title
+        .clone()
+        .unwrap_or(String::from("Unknown"));
+    println!(
+        "Playing '{}' by {}",
+        title,
+        music.header.writer.as_deref().unwrap_or("Unknown")
+    );
+    let mut completed = count_down(clock, options);
+
+    let beat = match music.header.bpm {
+        Some(bpm) => time::Duration::from_secs_f64(60.0 / bpm / options.speed),
+        None => durations.single,
+    };
+    let timeline = build_timeline(
+        music,
+        durations,
+        options,
+        &mut rng,
+        (options.start_at, options.loop_to),
+    );
+    let section = options.loop_to.map(|loop_to| {
+        let bounds = (options.loop_from, Some(loop_to));
+        (
+            bounds,
+            build_timeline(music, durations, options, &mut rng, bounds),
+        )
+    });
+    let mut progress = Progress {
+        title,
+        total: timeline.end,
+        elapsed: time::Duration::ZERO,
+        visible: options.progress,
+    };
+
+    // Counted in once the timelines are built, so building them can't come between the count and
+    // the first note.
+    completed = completed && count_in(clock, durations.single, options);
+    let finished = AtomicBool::new(false);
+    if completed {
+        completed = thread::scope(|scope| {
+            if options.metronome && !beat.is_zero() {
+                scope.spawn(|| run_metronome(clock, beat, options, &finished));
+            }
+
+            progress.update(time::Duration::ZERO);
+            let mut result = play_timeline(clock, output, &timeline, options, &mut progress);
+            if let Some((bounds, mut section)) = section {
+                // An empty section would go round without ever waiting.
+                while let Ok(true) = result
+                    && !section.end.is_zero()
+                {
+                    progress.total = section.end;
+                    result = play_timeline(clock, output, &section, options, &mut progress);
+                    // Jitter is drawn afresh each time round, so the passes don't all come out
+                    // the same.
+                    if options.jitter > 0.0
+                        && let Ok(true) = result
+                    {
+                        section = build_timeline(music, durations, options, &mut rng, bounds);
+                    }
+                }
+            }
+            finished.store(true, Ordering::Relaxed);
+            progress.finish();
+            result
+        })?;
+    }
+
+    if !completed {
+        println!("Playback aborted.");
+    }
+
+    Ok(())
+}
+
+/// Plays sheets to one output with the same options, working out each sheet's durations from
+/// its length.
+pub struct Player<O: KeyOutput, C: Clock = SystemClock> {
+    pub output: O,
+    pub options: PlayOptions,
+    pub clock: C,
+    pub distribution: PauseDistribution,
+}
+
+impl<O: KeyOutput> Player<O> {
+    pub fn new(output: O, distribution: PauseDistribution) -> Self {
+        Player {
+            output,
+            options: PlayOptions::default(),
+            clock: SystemClock,
+            distribution,
+        }
+    }
+}
+
+impl<O: KeyOutput, C: Clock> Player<O, C> {
+    pub fn speed(mut self, speed: f64) -> Self {
+        self.options.speed = speed;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.options.jitter = jitter;
+        self
+    }
+
+    pub fn countdown(mut self, seconds: u32) -> Self {
+        self.options.countdown = seconds;
+        self
+    }
+
+    pub fn count_in(mut self, beats: u32) -> Self {
+        self.options.count_in = beats;
+        self
+    }
+
+    pub fn repeat(mut self, times: u32) -> Self {
+        self.options.repeat = times;
+        self
+    }
+
+    /// Replaces every option at once, for settings without a setter of their own.
+    pub fn options(mut self, options: PlayOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn clock<T: Clock>(self, clock: T) -> Player<O, T> {
+        Player {
+            output: self.output,
+            options: self.options,
+            clock,
+            distribution: self.distribution,
+        }
+    }
+
+    /// Times key events on the output with [`calibrate`], so that playback makes up for them.
+    pub fn calibrate(&mut self) -> Result<time::Duration, PlayError> {
+        self.options.latency = calibrate(&mut self.output)?;
+        Ok(self.options.latency)
+    }
+
+    /// Plays `sheet` from the start, clearing an abort or pause left over from the last one.
+    pub fn play(&mut self, sheet: &Sheet) -> Result<(), PlayError> {
+        let durations =
+            calculate_token_durations_for(sheet, &self.distribution).map_err(PlayError::Sheet)?;
+        debug!("Token durations: {:?}", durations);
+
+        self.options.abort.store(false, Ordering::Relaxed);
+        self.options.paused.store(false, Ordering::Relaxed);
+        play_sheet_with_clock(
+            &self.clock,
+            &mut self.output,
+            sheet,
+            &durations,
+            &self.options,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sheet::parse_sheet,
+        testing::{FakeClock, RecordingKeyboard},
+    };
+
+    fn distribution() -> PauseDistribution {
+        PauseDistribution {
+            short: 0.2,
+            standard: 0.3,
+            long: 0.5,
+            pause_ratio: 20.0,
+            many_fast_proportion: 0.15,
+            many_fast_gap: 0.0,
+            chord_hold: 1.0,
+        }
+    }
+
+    fn options() -> PlayOptions {
+        PlayOptions {
+            countdown: 0,
+            seed: Some(1),
+            ..PlayOptions::default()
+        }
+    }
+
+    fn timeline(sheet: &Sheet, options: &PlayOptions) -> Timeline {
+        let durations = calculate_token_durations_for(sheet, &distribution()).unwrap();
+        let mut rng = prepare(options).unwrap();
+        build_timeline(
+            sheet,
+            &durations,
+            options,
+            &mut rng,
+            (time::Duration::ZERO, None),
+        )
+    }
+
+    fn presses(timeline: &Timeline) -> Vec<time::Duration> {
+        timeline
+            .events
+            .iter()
+            .filter(|event| matches!(event.direction, Direction::Press))
+            .map(|event| event.at)
+            .collect()
+    }
+
+    /// Plays `sheet` on a fake clock, returning the key events with their times from the start.
+    fn play(sheet: &str, options: &PlayOptions) -> Vec<(time::Duration, Direction, Key)> {
+        let sheet = parse_sheet(sheet).unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let mut keyboard = RecordingKeyboard::with_clock(clock.clone());
+        play_sheet_with_clock(&clock, &mut keyboard, &sheet, &durations, options).unwrap();
+        keyboard
+            .events()
+            .iter()
+            .map(|event| (event.at - start, event.direction, event.key))
+            .collect()
+    }
+
+    #[test]
+    fn chords_hold_their_keys_and_arpeggios_play_them_in_turn() {
+        use Direction::{Press, Release};
+        let keys = |events: &[(time::Duration, Direction, Key)]| {
+            events
+                .iter()
+                .map(|&(_, direction, key)| (direction, key))
+                .collect::<Vec<_>>()
+        };
+        let key = Key::Unicode;
+
+        let events = play("#length 2\n[asd] [f gh]", &options());
+        let (chord, arpeggio) = events.split_at(6);
+        assert_eq!(
+            keys(chord),
+            [
+                (Press, key('a')),
+                (Press, key('s')),
+                (Press, key('d')),
+                (Release, key('a')),
+                (Release, key('s')),
+                (Release, key('d')),
+            ]
+        );
+        assert!(chord[..3].iter().all(|&(at, ..)| at == chord[0].0));
+        assert_eq!(
+            keys(arpeggio),
+            [
+                (Press, key('f')),
+                (Release, key('f')),
+                (Press, key('g')),
+                (Release, key('g')),
+                (Press, key('h')),
+                (Release, key('h')),
+            ]
+        );
+        assert!(arpeggio.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert!(arpeggio[2].0 > arpeggio[0].0);
+    }
+
+    #[test]
+    fn swung_pair_keeps_its_total() {
+        let sheet = parse_sheet("#length 2\na s").unwrap();
+        let straight = timeline(&sheet, &options());
+        let swung = timeline(
+            &sheet,
+            &PlayOptions {
+                swing: 0.66,
+                ..options()
+            },
+        );
+
+        assert_eq!(swung.end, straight.end);
+        // The on-beat note is lengthened, so the off-beat one starts late.
+        assert!(presses(&swung)[1] > presses(&straight)[1]);
+    }
+
+    #[test]
+    fn swing_keeps_the_length_of_a_sheet() {
+        let sheet = parse_sheet("#length 8\na s d f | g h j k\n\nl z x c").unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let play = |swing| {
+            let clock = FakeClock::new();
+            let mut keyboard = RecordingKeyboard::with_clock(clock.clone());
+            let options = PlayOptions { swing, ..options() };
+            play_sheet_with_clock(&clock, &mut keyboard, &sheet, &durations, &options).unwrap();
+            clock.elapsed()
+        };
+
+        let straight = play(0.0);
+        let swung = play(0.66);
+        assert!(straight.abs_diff(swung) < time::Duration::from_millis(1));
+    }
+
+    #[test]
+    fn prepare_rejects_options_that_would_overflow() {
+        let rejected = [
+            PlayOptions {
+                jitter: f64::INFINITY,
+                ..PlayOptions::default()
+            },
+            PlayOptions {
+                jitter: -0.1,
+                ..PlayOptions::default()
+            },
+            PlayOptions {
+                speed: 1e-300,
+                ..PlayOptions::default()
+            },
+            PlayOptions {
+                speed: f64::NAN,
+                ..PlayOptions::default()
+            },
+            PlayOptions {
+                articulation: 1e300,
+                ..PlayOptions::default()
+            },
+            PlayOptions {
+                articulation: f64::INFINITY,
+                ..PlayOptions::default()
+            },
+        ];
+        for options in &rejected {
+            assert!(prepare(options).is_err());
+        }
+        assert!(prepare(&PlayOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn notes_lengthen_through_a_ritardando() {
+        let gaps = |input| {
+            let timeline = timeline(&parse_sheet(input).unwrap(), &options());
+            presses(&timeline)
+                .windows(2)
+                .map(|pair| pair[1] - pair[0])
+                .collect::<Vec<_>>()
+        };
+
+        let rit = gaps("#length 4\nqwer{rit 1.5}tyuiop{end}as");
+        assert!(
+            rit[4..9].windows(2).all(|pair| pair[1] > pair[0]),
+            "{:?}",
+            rit
+        );
+        // Outside the span the tempo is steady, and after it back to where it started.
+        assert_eq!(rit[0], rit[2]);
+        assert_eq!(rit[10], rit[0]);
+
+        let accel = gaps("#length 4\n{rit 0.5}qwerty{end}");
+        assert!(
+            accel.windows(2).all(|pair| pair[1] < pair[0]),
+            "{:?}",
+            accel
+        );
+    }
+
+    /// A keyboard that takes a while to send each key, as a real one does.
+    struct SlowKeyboard {
+        clock: FakeClock,
+        keys: RecordingKeyboard,
+    }
+
+    impl KeyOutput for SlowKeyboard {
+        fn press(&mut self, key: Key) -> Result<(), InputError> {
+            self.clock.sleep(time::Duration::from_millis(3));
+            self.keys.press(key)
+        }
+
+        fn release(&mut self, key: Key) -> Result<(), InputError> {
+            self.clock.sleep(time::Duration::from_millis(3));
+            self.keys.release(key)
+        }
+    }
+
+    #[test]
+    fn key_latency_does_not_add_up() {
+        let sheet = parse_sheet(&format!("#length 60\n{}", "qwer tyui|".repeat(50))).unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let mut keyboard = SlowKeyboard {
+            clock: clock.clone(),
+            keys: RecordingKeyboard::with_clock(clock.clone()),
+        };
+        play_sheet_with_clock(&clock, &mut keyboard, &sheet, &durations, &options()).unwrap();
+
+        // 400 notes of two 3ms key events each would end 2.4 seconds late if the waits were
+        // relative. Against the schedule, each press is only as late as the keys sent before it.
+        let expected = presses(&timeline(&sheet, &options()));
+        let pressed: Vec<_> = keyboard
+            .keys
+            .events()
+            .iter()
+            .filter(|event| matches!(event.direction, Direction::Press))
+            .map(|event| event.at - start)
+            .collect();
+        assert_eq!(pressed.len(), 400);
+        for (pressed, expected) in pressed.iter().zip(expected) {
+            assert!(pressed.abs_diff(expected) <= time::Duration::from_millis(6));
+        }
+        assert!(
+            clock.elapsed().abs_diff(time::Duration::from_secs(60))
+                < time::Duration::from_millis(10)
+        );
+    }
+
+    #[test]
+    fn shifted_notes_press_shift_around_their_base_key() {
+        use Direction::{Press, Release};
+        let events: Vec<_> = play("#length 1\na Q [s@]", &options())
+            .into_iter()
+            .map(|(_, direction, key)| (direction, key))
+            .collect();
+        let key = Key::Unicode;
+        assert_eq!(
+            events,
+            [
+                (Press, key('a')),
+                (Release, key('a')),
+                (Press, Key::Shift),
+                (Press, key('q')),
+                (Release, Key::Shift),
+                (Release, key('q')),
+                (Press, key('s')),
+                (Press, Key::Shift),
+                (Press, key('2')),
+                (Release, Key::Shift),
+                (Release, key('s')),
+                (Release, key('2')),
+            ]
+        );
+    }
+
+    /// A fake clock that sets the abort flag once it reaches `at`, like a hotkey pressed then.
+    struct AbortingClock {
+        clock: FakeClock,
+        abort: Arc<AtomicBool>,
+        at: time::Instant,
+    }
+
+    impl Clock for AbortingClock {
+        fn now(&self) -> time::Instant {
+            self.clock.now()
+        }
+
+        fn sleep(&self, duration: time::Duration) {
+            self.clock.sleep(duration);
+            if self.clock.now() >= self.at {
+                self.abort.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[test]
+    fn abort_interrupts_a_long_pause() {
+        let sheet = parse_sheet("#length 20\na\n\ns").unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let options = options();
+        let second_note = presses(&timeline(&sheet, &options))[1];
+        assert!(second_note > time::Duration::from_secs(2));
+
+        let clock = FakeClock::new();
+        let aborted_at = second_note - time::Duration::from_secs(1);
+        let aborting = AbortingClock {
+            clock: clock.clone(),
+            abort: options.abort.clone(),
+            at: clock.now() + aborted_at,
+        };
+        let mut keyboard = RecordingKeyboard::new();
+        _ = play_sheet_with_clock(&aborting, &mut keyboard, &sheet, &durations, &options);
+
+        // Only the first note was played, and playback stopped within one poll of the abort.
+        assert_eq!(keyboard.events().len(), 2);
+        assert!(clock.elapsed() >= aborted_at);
+        assert!(clock.elapsed() - aborted_at <= ABORT_POLL_INTERVAL);
+    }
+
+    #[test]
+    fn fake_clock_times_each_token_without_waiting() {
+        let sheet = parse_sheet("#length 30\nqwe|r t\n\nyu [io] p").unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let wall = time::Instant::now();
+        let events = play("#length 30\nqwe|r t\n\nyu [io] p", &options());
+        assert!(wall.elapsed() < time::Duration::from_secs(1));
+        // The last note is let go of as the sheet ends.
+        assert_eq!(
+            events.last().unwrap().0,
+            sheet.estimated_duration(&durations)
+        );
+
+        let mut pressed: Vec<_> = events
+            .into_iter()
+            .filter(|&(_, direction, _)| matches!(direction, Direction::Press))
+            .map(|(at, ..)| at)
+            .collect();
+
+        let starts: Vec<_> = sheet
+            .schedule(&durations)
+            .filter(|(_, token, _)| !token.keys().is_empty())
+            .map(|(start, ..)| start)
+            .collect();
+        // The chord presses both of its keys at its start.
+        assert_eq!(pressed.len(), starts.len() + 1);
+        pressed.dedup();
+        assert_eq!(pressed, starts);
+    }
+
+    #[test]
+    fn articulation_splits_notes_into_hold_and_gap() {
+        // When each key goes down and comes up again.
+        let notes = |articulation| {
+            let events = play(
+                "#length 2\nasdf",
+                &PlayOptions {
+                    articulation,
+                    ..options()
+                },
+            );
+            "asdf"
+                .chars()
+                .map(|character| {
+                    let at = |wanted: Direction| {
+                        events
+                            .iter()
+                            .find(|&&(_, direction, key)| {
+                                direction == wanted && key == Key::Unicode(character)
+                            })
+                            .unwrap()
+                            .0
+                    };
+                    (at(Direction::Press), at(Direction::Release))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let normal = notes(1.0);
+        let staccato = notes(0.5);
+        let legato = notes(1.25);
+        for index in 0..4 {
+            let (press, release) = normal[index];
+            let allotted = release - press;
+            assert_eq!(staccato[index].0, press);
+            assert!(
+                staccato[index].1.abs_diff(press + allotted / 2) < time::Duration::from_micros(1)
+            );
+            assert_eq!(legato[index].0, press);
+            assert!(
+                legato[index].1.abs_diff(press + allotted.mul_f64(1.25))
+                    < time::Duration::from_micros(1)
+            );
+        }
+        // Staccato leaves a gap before the next note, and legato overlaps it.
+        assert!(staccato[0].1 < staccato[1].0);
+        assert!(legato[0].1 > legato[1].0);
+    }
+
+    #[test]
+    fn arpeggio_notes_are_spaced_by_the_gap() {
+        let sheet = parse_sheet("#length 2\na [s df]").unwrap();
+        // The silences between one key of the arpeggio coming up and the next going down.
+        let gaps = |many_fast_gap| {
+            let distribution = PauseDistribution {
+                many_fast_gap,
+                ..distribution()
+            };
+            let durations = calculate_token_durations_for(&sheet, &distribution).unwrap();
+            let clock = FakeClock::new();
+            let mut keyboard = RecordingKeyboard::with_clock(clock.clone());
+            play_sheet_with_clock(&clock, &mut keyboard, &sheet, &durations, &options()).unwrap();
+            keyboard.events()[2..]
+                .chunks(2)
+                .collect::<Vec<_>>()
+                .windows(2)
+                .map(|notes| notes[1][0].at - notes[0][1].at)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(gaps(0.0), [time::Duration::ZERO; 2]);
+        assert_eq!(gaps(0.05), [time::Duration::from_millis(50); 2]);
+    }
+
+    /// A keyboard that fails hard after a number of presses.
+    struct PanickingKeyboard {
+        keys: RecordingKeyboard,
+        presses_left: usize,
+    }
+
+    impl KeyOutput for PanickingKeyboard {
+        fn press(&mut self, key: Key) -> Result<(), InputError> {
+            if self.presses_left == 0 {
+                panic!("keyboard unplugged");
+            }
+            self.presses_left -= 1;
+            self.keys.press(key)
+        }
+
+        fn release(&mut self, key: Key) -> Result<(), InputError> {
+            self.keys.release(key)
+        }
+    }
+
+    #[test]
+    fn panic_in_a_chord_still_releases_its_keys() {
+        use Direction::{Press, Release};
+        let sheet = parse_sheet("#length 1\nq [asd]").unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let clock = FakeClock::new();
+        let mut keyboard = PanickingKeyboard {
+            keys: RecordingKeyboard::new(),
+            presses_left: 3,
+        };
+        let played = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            play_sheet_with_clock(&clock, &mut keyboard, &sheet, &durations, &options())
+        }));
+        assert!(played.is_err());
+
+        let mut events: Vec<_> = keyboard
+            .keys
+            .events()
+            .iter()
+            .map(|event| (event.direction, event.key))
+            .collect();
+        let key = Key::Unicode;
+        assert_eq!(
+            events[..4],
+            [
+                (Press, key('q')),
+                (Release, key('q')),
+                (Press, key('a')),
+                (Press, key('s')),
+            ]
+        );
+        events[4..].sort_by_key(|&(_, key)| format!("{:?}", key));
+        assert_eq!(events[4..], [(Release, key('a')), (Release, key('s'))]);
+    }
+
+    /// When each token of `sheet` starts, as the player times it.
+    fn starts(sheet: &str) -> Vec<time::Duration> {
+        let sheet = parse_sheet(sheet).unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        sheet
+            .schedule(&durations)
+            .map(|(start, ..)| start)
+            .collect()
+    }
+
+    #[test]
+    fn start_at_skips_the_tokens_before_it() {
+        use Direction::Press;
+        let sheet = "#length 4\na~ s d|f ~a g";
+        let starts = starts(sheet);
+        let key = Key::Unicode;
+
+        // From just into `d`, the next note is the first one played, with the sustain still down.
+        let from_d = PlayOptions {
+            start_at: starts[4] + time::Duration::from_millis(1),
+            ..options()
+        };
+        let events = play(sheet, &from_d);
+        assert_eq!(events[0], (time::Duration::ZERO, Press, key('a')));
+        assert_eq!(events[1].1, Press);
+        assert_eq!(events[1].2, key('f'));
+        assert!(
+            !events
+                .iter()
+                .any(|&(_, _, played)| played == key('s') || played == key('d'))
+        );
+
+        // Exactly at a token's start plays it, and after the release the sustain isn't pressed.
+        let at_g = PlayOptions {
+            start_at: starts[10],
+            ..options()
+        };
+        let events = play(sheet, &at_g);
+        assert_eq!(events[0], (time::Duration::ZERO, Press, key('g')));
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn loop_lets_go_of_notes_held_over_its_end() {
+        use Direction::{Press, Release};
+        let sheet = "#length 4\nq w~ e r ~w t";
+        let starts = starts(sheet);
+        let options = PlayOptions {
+            loop_from: starts[4],
+            loop_to: Some(starts[8]),
+            ..options()
+        };
+        let parsed = parse_sheet(sheet).unwrap();
+        let durations = calculate_token_durations_for(&parsed, &distribution()).unwrap();
+        let clock = FakeClock::new();
+        let aborting = AbortingClock {
+            clock: clock.clone(),
+            abort: options.abort.clone(),
+            at: clock.now() + time::Duration::from_secs(10),
+        };
+        let mut keyboard = RecordingKeyboard::new();
+        _ = play_sheet_with_clock(&aborting, &mut keyboard, &parsed, &durations, &options);
+
+        let key = Key::Unicode;
+        let pressed: Vec<_> = keyboard
+            .events()
+            .iter()
+            .filter(|event| event.direction == Press)
+            .map(|event| event.key)
+            .collect();
+        // Once through to the end of the loop, then round from `e` until aborted, never reaching
+        // `t`. Each time round starts by pressing the sustain that is down at `e` again.
+        assert_eq!(
+            pressed[..7],
+            [
+                key('q'),
+                key('w'),
+                key('e'),
+                key('r'),
+                key('w'),
+                key('e'),
+                key('r')
+            ]
+        );
+        assert!(pressed.len() > 10);
+        assert!(!pressed.contains(&key('t')));
+
+        // Every key is let go of before it's pressed again, and nothing is left down.
+        let mut down = Vec::new();
+        for event in keyboard.events() {
+            match event.direction {
+                Release => assert!(down.contains(&event.key)),
+                _ => assert!(!down.contains(&event.key)),
+            }
+            down.retain(|key| *key != event.key);
+            if event.direction == Press {
+                down.push(event.key);
+            }
+        }
+        assert!(down.is_empty());
+    }
+
+    #[test]
+    fn loop_start_needs_an_end() {
+        let sheet = parse_sheet("#length 2\nq w e").unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let options = PlayOptions {
+            loop_from: time::Duration::from_secs(1),
+            ..options()
+        };
+        let mut keyboard = RecordingKeyboard::new();
+        let played = play_sheet_with_clock(
+            &FakeClock::new(),
+            &mut keyboard,
+            &sheet,
+            &durations,
+            &options,
+        );
+        assert!(matches!(played, Err(PlayError::LoopWithoutEnd)));
+        assert!(keyboard.events().is_empty());
+    }
+
+    #[test]
+    fn loop_jitter_changes_every_time_round() {
+        let sheet = "#length 4\nq w e r t";
+        let starts = starts(sheet);
+        let options = PlayOptions {
+            jitter: 0.5,
+            loop_from: starts[2],
+            loop_to: Some(starts[8]),
+            ..options()
+        };
+        let parsed = parse_sheet(sheet).unwrap();
+        let durations = calculate_token_durations_for(&parsed, &distribution()).unwrap();
+        let clock = FakeClock::new();
+        let aborting = AbortingClock {
+            clock: clock.clone(),
+            abort: options.abort.clone(),
+            at: clock.now() + time::Duration::from_secs(20),
+        };
+        let mut keyboard = RecordingKeyboard::with_clock(clock.clone());
+        _ = play_sheet_with_clock(&aborting, &mut keyboard, &parsed, &durations, &options);
+
+        // How long after each `w` its `e` comes, which only jitter changes from one pass to the
+        // next.
+        let presses: Vec<_> = keyboard
+            .events()
+            .iter()
+            .filter(|event| event.direction == Direction::Press)
+            .map(|event| (event.key, event.at))
+            .collect();
+        let gaps: Vec<_> = presses
+            .windows(2)
+            .filter(|pair| pair[0].0 == Key::Unicode('w') && pair[1].0 == Key::Unicode('e'))
+            .map(|pair| pair[1].1 - pair[0].1)
+            .collect();
+        // The first pass comes from the timeline up to the loop, so only the later ones are the
+        // loop's own.
+        assert!(gaps.len() > 3);
+        assert!(
+            gaps[1..].windows(2).any(|pair| pair[0] != pair[1]),
+            "{:?}",
+            gaps
+        );
+    }
+
+    /// A fake clock whose plain sleeps overshoot like a coarse OS timer, while sleeping until a
+    /// deadline is exact.
+    struct CoarseClock(FakeClock);
+
+    impl Clock for CoarseClock {
+        fn now(&self) -> time::Instant {
+            self.0.now()
+        }
+
+        fn sleep(&self, duration: time::Duration) {
+            self.0.sleep(duration + time::Duration::from_millis(15));
+        }
+
+        fn sleep_until(&self, deadline: time::Instant) {
+            self.0.sleep_until(deadline);
+        }
+    }
+
+    #[test]
+    fn deadlines_are_met_despite_a_coarse_timer() {
+        let sheet = parse_sheet("#length 20\nqwer\n\ntyui|op").unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let clock = CoarseClock(FakeClock::new());
+        let start = clock.now();
+        let mut keyboard = RecordingKeyboard::with_clock(clock.0.clone());
+        play_sheet_with_clock(&clock, &mut keyboard, &sheet, &durations, &options()).unwrap();
+
+        let expected = presses(&timeline(&sheet, &options()));
+        let pressed: Vec<_> = keyboard
+            .events()
+            .iter()
+            .filter(|event| event.direction == Direction::Press)
+            .map(|event| event.at - start)
+            .collect();
+        assert_eq!(pressed.len(), expected.len());
+        for (pressed, expected) in pressed.into_iter().zip(expected) {
+            assert_eq!(pressed, expected);
+        }
+    }
+}
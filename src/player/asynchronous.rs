@@ -0,0 +1,62 @@
+use tokio::time::{Duration, Instant, sleep_until};
+use tokio_util::sync::CancellationToken;
+
+use super::{PlayError, PlayOptions, PressedKeys, build_timeline, prepare};
+use crate::{
+    output::KeyOutput,
+    sheet::{Sheet, TokenDurations},
+};
+
+/// Plays `music` like [`play_sheet`](super::play_sheet), but awaits between key events instead of
+/// blocking the thread. Cancelling `cancel` releases any keys that are down and returns
+/// `Ok(false)`; a performance that runs to the end returns `Ok(true)`. Dropping the future part
+/// way through lets go of the keys too.
+///
+/// Only the timing options apply: there is no countdown, count-in, metronome, progress bar or A-B
+/// loop, and the abort and pause flags are not watched.
+pub async fn play_sheet_async(
+    output: &mut impl KeyOutput,
+    music: &Sheet,
+    durations: &TokenDurations,
+    options: &PlayOptions,
+    cancel: &CancellationToken,
+) -> Result<bool, PlayError> {
+    let mut rng = prepare(options)?;
+    let durations = durations.scaled(options.speed);
+    let timeline = build_timeline(
+        music,
+        &durations,
+        options,
+        &mut rng,
+        (options.start_at, None),
+    );
+
+    let start = Instant::now();
+    let at = |offset: Duration| start + offset;
+    let mut keys = PressedKeys::new(output);
+    let mut result = Ok(true);
+    for event in &timeline.events {
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                result = Ok(false);
+                break;
+            }
+            _ = sleep_until(at(event.at.saturating_sub(options.latency))) => {}
+        }
+
+        if let Err(err) = keys.send(event.key, event.direction) {
+            result = Err(err);
+            break;
+        }
+    }
+
+    if let Ok(true) = result {
+        tokio::select! {
+            _ = cancel.cancelled() => result = Ok(false),
+            _ = sleep_until(at(timeline.end)) => {}
+        }
+    }
+
+    let released = keys.release_all();
+    result.and_then(|completed| released.map(|()| completed))
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    sync::mpsc,
+    thread,
+    time::Instant,
+};
+
+use enigo::Key;
+use rdev::EventType;
+
+use crate::{
+    layout,
+    sheet::{Header, Sheet, tokens_from_onsets},
+};
+
+#[derive(Debug)]
+pub enum RecordError {
+    Listen(rdev::ListenError),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::Listen(err) => write!(f, "Could not listen for key presses: {:?}", err),
+        }
+    }
+}
+
+impl Error for RecordError {}
+
+/// Builds a sheet from VirtualPiano keys paired with the seconds into the recording they were
+/// pressed at, in order. Presses close together become chords and the gaps become pauses.
+pub fn sheet_from_presses(presses: &[(f64, char)], length: f64) -> Sheet {
+    let onsets: Vec<(f64, Key)> = presses
+        .iter()
+        .map(|&(secs, character)| (secs, Key::Unicode(character)))
+        .collect();
+
+    Sheet {
+        header: Header {
+            title: None,
+            writer: None,
+            length,
+            bpm: None,
+            metadata: HashMap::new(),
+        },
+        tokens: tokens_from_onsets(&onsets),
+    }
+}
+
+enum Captured {
+    Press(Instant, rdev::Key, Option<String>),
+    Release(rdev::Key),
+    Failed(rdev::ListenError),
+}
+
+/// Listens to the keyboard and records the VirtualPiano keys played between two presses of
+/// `toggle`. Other keys, and the repeats sent while a key is held down, are ignored. `#length`
+/// is the time between the two toggles.
+pub fn record(toggle: rdev::Key) -> Result<Sheet, RecordError> {
+    let (sender, events) = mpsc::channel();
+    // The listener can't be stopped, so it is left running for the rest of the process.
+    thread::spawn(move || {
+        let callback_sender = sender.clone();
+        let result = rdev::listen(move |event| {
+            let captured = match event.event_type {
+                EventType::KeyPress(key) => Captured::Press(Instant::now(), key, event.name),
+                EventType::KeyRelease(key) => Captured::Release(key),
+                _ => return,
+            };
+            _ = callback_sender.send(captured);
+        });
+        if let Err(err) = result {
+            _ = sender.send(Captured::Failed(err));
+        }
+    });
+
+    let mut start = None;
+    let mut presses = Vec::new();
+    let mut down = HashSet::new();
+    for captured in events {
+        let (at, key, name) = match captured {
+            Captured::Failed(err) => return Err(RecordError::Listen(err)),
+            Captured::Release(key) => {
+                down.remove(&key);
+                continue;
+            }
+            Captured::Press(at, key, name) => (at, key, name),
+        };
+        if !down.insert(key) {
+            continue;
+        }
+
+        if key == toggle {
+            match start {
+                None => {
+                    start = Some(at);
+                    println!("Recording, press {:?} again to stop.", toggle);
+                    continue;
+                }
+                Some(start) => {
+                    let length = (at - start).as_secs_f64();
+                    return Ok(sheet_from_presses(&presses, length));
+                }
+            }
+        }
+
+        let Some(start) = start else {
+            continue;
+        };
+        // The typed character already accounts for Shift, so `!` is recorded rather than `1`.
+        if let Some(character) = name.and_then(|name| name.chars().next())
+            && layout::index_of(character).is_some()
+        {
+            presses.push(((at - start).as_secs_f64(), character));
+        }
+    }
+
+    Ok(sheet_from_presses(&presses, 0.0))
+}
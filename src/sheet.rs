@@ -1,64 +1,673 @@
-use std::collections::HashMap;
+//! Parsing of the plain-text sheet format.
+//!
+//! A sheet is a list of `#name value` defines (`#title`, `#writer`, and `#length` as `mm:ss`,
+//! `h:mm:ss`, seconds or `3m40s`, or `#bpm` with optional `#beats`) followed by lines of notes:
+//!
+//! - Each character is a VirtualPiano key. `[abc]` plays a chord and `[a bc]` (any space inside
+//!   the brackets) plays the keys as a fast arpeggio.
+//! - A space is a short pause, `|` a pause, and `||` or a blank line a long pause. Each space is a pause
+//!   of its own unless the sheet sets `#spaces scaled`, which makes a run of spaces one pause that
+//!   is as long as all of them together. `.2` rests for as long
+//!   as two notes, and `.0.5` for half a note. Tabs are ignored, so lines can be indented with them
+//!   without adding pauses.
+//! - `<` and `>` shift every following note down or up an octave, until shifted back.
+//! - `a:2` holds a note or chord for twice as long. `*` is a key, so `a*4` only repeats a note or
+//!   chord four times in a sheet that sets `#repetition on`.
+//! - `|:` ... `:|` plays the section in between twice, and `:|x3` three times.
+//! - A line that is only `@name` starts a section that runs until the next label or jump, and a
+//!   later line that is only `>>name` plays that section again from there. Jumping to a section
+//!   that has not been labelled yet is an error. `@` is still a key anywhere else, so a line of
+//!   notes that would read as a label needs its `@` escaped (`\@w`).
+//! - `{rit}` ... `{end}` gradually slows the notes in between to 1.5x their length, and
+//!   `{accel}` ... `{end}` speeds them up to 1/1.5x. A target such as `{rit 2}` overrides that.
+//! - `{3 abc}` plays the notes inside as a tuplet: three in the time of two. In general `{N ...}`
+//!   fits its notes into the time of the largest power of two below `N`.
+//! - `a_` ties a note into the next note, which must be the same key: the key stays down through
+//!   any pauses or line breaks in between instead of being pressed again.
+//! - `a~` presses a note and keeps it held under the following notes until a later `~a`.
+//! - `$name` inserts the value of `#name`, `\` makes the next character a literal key, and `//`
+//!   starts a comment.
+//!
+//! Sheets are NFC-normalized before parsing, and typographic quotes, dashes and non-breaking
+//! spaces are read as their ASCII equivalents. Any other non-ASCII character is skipped with a
+//! [`SheetWarning`] unless it is escaped or remapped.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    io::{self, BufRead},
+    iter::{Enumerate, Peekable},
+    ops::Range,
+    str::Chars,
+    time::Duration,
+};
 
 use enigo::Key;
+use smallvec::SmallVec;
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Clone)]
+use crate::layout::{self, ClampPolicy};
+
+mod cache;
+mod json;
+mod load;
+mod midi;
+mod onsets;
+mod text;
+mod virtualpiano;
+
+pub use cache::SheetCache;
+pub use json::{from_json, to_json};
+pub use load::{
+    JsonParser, MidiParser, SheetParser, SheetParsers, TextParser, VirtualPianoParser, load_any,
+};
+pub use midi::{from_midi, to_midi};
+pub(crate) use onsets::tokens_from_onsets;
+pub use text::to_string;
+pub use virtualpiano::{from_virtualpiano, to_virtualpiano_string};
+
+/// The keys of a chord or fast arpeggio. Most have only a few keys, which are stored inline.
+pub type Chord = SmallVec<[Key; 4]>;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Token {
     ShortPause,
+    /// A run of this many spaces under `#spaces scaled`, played as a single pause.
+    ShortPauses(usize),
     Pause,
     LongPause,
-    Single(Key),
-    Many(Vec<Key>),
-    ManyFast(Vec<Key>),
+    /// A rest lasting this many notes (`.2`).
+    Rest(f64),
+    /// A note, with an optional factor applied to how long it is held (`a:2`).
+    Single(Key, Option<f64>),
+    /// A chord, with an optional factor applied to how long it is held (`[abc]:2`).
+    Many(Chord, Option<f64>),
+    ManyFast(Chord),
+    /// Start of a `{rit}` or `{accel}` span: durations are scaled gradually from their normal
+    /// length up to this factor by the matching `TempoEnd`.
+    TempoStart(f64),
+    TempoEnd,
+    /// A note tied into the next note with `_` (`a_`). The key stays down through any pauses, and
+    /// the next note, always the same key, carries on without pressing it again.
+    Tied(Key, Option<f64>),
+    /// A note that is pressed for the length of a single note, then stays down (`a~`).
+    Hold(Key),
+    /// Lets go of a key held by an earlier `Hold` (`~a`). Takes no time.
+    ReleaseHold(Key),
+}
+
+impl Token {
+    /// The keys the token presses or releases; empty for pauses and markers.
+    pub fn keys(&self) -> &[Key] {
+        match self {
+            Token::Single(key, _)
+            | Token::Tied(key, _)
+            | Token::Hold(key)
+            | Token::ReleaseHold(key) => std::slice::from_ref(key),
+            Token::Many(keys, _) | Token::ManyFast(keys) => keys,
+            Token::ShortPause
+            | Token::ShortPauses(_)
+            | Token::Pause
+            | Token::LongPause
+            | Token::Rest(_)
+            | Token::TempoStart(_)
+            | Token::TempoEnd => &[],
+        }
+    }
+
+    /// How long the token takes to play at its nominal tempo, ignoring swing and jitter.
+    pub fn duration(&self, durations: &TokenDurations) -> Duration {
+        match self {
+            Token::ShortPause => durations.short_pause,
+            Token::ShortPauses(spaces) => durations.short_pause * *spaces as u32,
+            Token::Pause => durations.pause,
+            Token::LongPause => durations.long_pause,
+            Token::Rest(notes) => durations.single.mul_f64(*notes),
+            Token::Single(_, length) | Token::Tied(_, length) | Token::Many(_, length) => {
+                durations.single.mul_f64(length.unwrap_or(1.0))
+            }
+            Token::Hold(_) => durations.single,
+            Token::ManyFast(keys) => {
+                durations.many_fast * keys.len() as u32
+                    + durations.many_fast_gap * keys.len().saturating_sub(1) as u32
+            }
+            Token::TempoStart(_) | Token::TempoEnd | Token::ReleaseHold(_) => Duration::ZERO,
+        }
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TokenDurations {
-    pub short_pause: f64,
-    pub pause: f64,
-    pub long_pause: f64,
-    pub single: f64,
-    pub many_fast: f64,
+    pub short_pause: Duration,
+    pub pause: Duration,
+    pub long_pause: Duration,
+    pub single: Duration,
+    pub many_fast: Duration,
+    /// Silence between the notes of a fast arpeggio.
+    pub many_fast_gap: Duration,
+    /// How many times its length a chord's keys stay down, so chords can ring under later notes.
+    pub chord_hold: f64,
 }
 
-#[derive(Clone)]
+impl TokenDurations {
+    /// Durations given in seconds, which must be finite and not negative.
+    pub fn from_secs(
+        short_pause: f64,
+        pause: f64,
+        long_pause: f64,
+        single: f64,
+        many_fast: f64,
+        many_fast_gap: f64,
+    ) -> Result<TokenDurations, SheetError> {
+        let secs = |secs: f64| {
+            Duration::try_from_secs_f64(secs).map_err(|_| {
+                SheetError::InvalidDistribution("Durations must be finite and not negative")
+            })
+        };
+
+        Ok(TokenDurations {
+            short_pause: secs(short_pause)?,
+            pause: secs(pause)?,
+            long_pause: secs(long_pause)?,
+            single: secs(single)?,
+            many_fast: secs(many_fast)?,
+            many_fast_gap: secs(many_fast_gap)?,
+            chord_hold: 1.0,
+        })
+    }
+
+    /// Durations for playback at `speed` times the normal tempo, so `2.0` halves every duration.
+    pub fn scaled(&self, speed: f64) -> TokenDurations {
+        TokenDurations {
+            short_pause: self.short_pause.div_f64(speed),
+            pause: self.pause.div_f64(speed),
+            long_pause: self.long_pause.div_f64(speed),
+            single: self.single.div_f64(speed),
+            many_fast: self.many_fast.div_f64(speed),
+            many_fast_gap: self.many_fast_gap.div_f64(speed),
+            chord_hold: self.chord_hold,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Header {
     pub title: Option<String>,
     pub writer: Option<String>,
     pub length: f64,
+    pub bpm: Option<f64>,
+    /// Every `#name value` define in the sheet, including the ones above, keyed by name without
+    /// the `#`.
+    pub metadata: HashMap<String, String>,
 }
 
+/// The iterator returned by [`Sheet::schedule`], yielding `(start, token, length)`.
 #[derive(Clone)]
+pub struct TokenSchedule<'a> {
+    tokens: std::iter::Zip<std::slice::Iter<'a, Token>, TempoFactors<'a>>,
+    durations: &'a TokenDurations,
+    time: Duration,
+}
+
+impl<'a> Iterator for TokenSchedule<'a> {
+    type Item = (Duration, &'a Token, Duration);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (token, factor) = self.tokens.next()?;
+        let start = self.time;
+        let length = token.duration(self.durations).mul_f64(factor);
+        self.time += length;
+        Some((start, token, length))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.tokens.size_hint()
+    }
+}
+
+#[derive(Debug)]
+pub struct SheetStats {
+    pub singles: usize,
+    pub chords: usize,
+    pub fast_arpeggios: usize,
+    pub short_pauses: usize,
+    pub pauses: usize,
+    pub long_pauses: usize,
+    pub rests: usize,
+    /// Length from the header, in seconds.
+    pub declared_length: f64,
+    pub estimated_length: Duration,
+    /// The key played most often, with how many times it is played.
+    pub most_used: Option<(char, usize)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Sheet {
     pub header: Header,
     pub tokens: Vec<Token>,
 }
 
+impl Sheet {
+    /// Number of tokens that play notes: singles, holds, chords and fast arpeggios.
+    pub fn note_count(&self) -> usize {
+        count_notes(&self.tokens)
+    }
+
+    /// Total time spent in pause tokens and between the notes of fast arpeggios at the given
+    /// durations.
+    pub fn pause_time(&self, durations: &TokenDurations) -> Duration {
+        self.tokens
+            .iter()
+            .map(|token| match token {
+                Token::ShortPause
+                | Token::ShortPauses(_)
+                | Token::Pause
+                | Token::LongPause
+                | Token::Rest(_) => token.duration(durations),
+                Token::ManyFast(keys) => {
+                    durations.many_fast_gap * keys.len().saturating_sub(1) as u32
+                }
+                _ => Duration::ZERO,
+            })
+            .sum()
+    }
+
+    /// How long playing the sheet once with `durations` takes, including `{rit}`/`{accel}` spans.
+    pub fn estimated_duration(&self, durations: &TokenDurations) -> Duration {
+        self.schedule(durations).map(|(_, _, length)| length).sum()
+    }
+
+    /// Each token with when it starts and how long it takes to play with `durations`, after
+    /// `{rit}`/`{accel}` spans but without swing or jitter.
+    pub fn schedule<'a>(&'a self, durations: &'a TokenDurations) -> TokenSchedule<'a> {
+        TokenSchedule {
+            tokens: self.tokens.iter().zip(tempo_factors(&self.tokens)),
+            durations,
+            time: Duration::ZERO,
+        }
+    }
+
+    pub fn stats(&self, durations: &TokenDurations) -> SheetStats {
+        let mut stats = SheetStats {
+            singles: 0,
+            chords: 0,
+            fast_arpeggios: 0,
+            short_pauses: 0,
+            pauses: 0,
+            long_pauses: 0,
+            rests: 0,
+            declared_length: self.header.length,
+            estimated_length: self.estimated_duration(durations),
+            most_used: None,
+        };
+
+        let mut uses = HashMap::new();
+        let mut count = |keys: &[Key]| {
+            for key in keys {
+                if let Key::Unicode(character) = key {
+                    *uses.entry(*character).or_insert(0) += 1;
+                }
+            }
+        };
+        for token in &self.tokens {
+            match token {
+                Token::ShortPause => stats.short_pauses += 1,
+                // Counted per space, so the statistics don't depend on `#spaces`.
+                Token::ShortPauses(spaces) => stats.short_pauses += spaces,
+                Token::Pause => stats.pauses += 1,
+                Token::LongPause => stats.long_pauses += 1,
+                Token::Rest(_) => stats.rests += 1,
+                Token::Single(key, _) | Token::Tied(key, _) | Token::Hold(key) => {
+                    stats.singles += 1;
+                    count(std::slice::from_ref(key));
+                }
+                Token::Many(keys, _) => {
+                    stats.chords += 1;
+                    count(keys);
+                }
+                Token::ManyFast(keys) => {
+                    stats.fast_arpeggios += 1;
+                    count(keys);
+                }
+                Token::TempoStart(_) | Token::TempoEnd | Token::ReleaseHold(_) => {}
+            }
+        }
+
+        // Ties go to the smallest character so the result doesn't depend on hash order.
+        stats.most_used = uses
+            .into_iter()
+            .max_by_key(|&(character, uses)| (uses, std::cmp::Reverse(character)));
+        stats
+    }
+
+    /// Moves every note `steps` keys along the VirtualPiano layout and returns how many notes had
+    /// to be clamped to the edge of the keyboard.
+    pub fn transpose(&mut self, steps: i32) -> usize {
+        self.transpose_with(steps, ClampPolicy::Nearest)
+            .expect("clamping never fails")
+    }
+
+    /// Moves every note `steps` keys along the VirtualPiano layout, handling the notes that end up
+    /// past the edge of the keyboard as `policy` says, and returns how many notes that was.
+    ///
+    /// A dropped note becomes a rest, so the notes after it keep their timing, and a chord keeps
+    /// the notes that still fit. With [`ClampPolicy::Error`] the sheet is left as it was.
+    pub fn transpose_with(&mut self, steps: i32, policy: ClampPolicy) -> Result<usize, SheetError> {
+        let mut adjusted = 0;
+        // `None` when the note is dropped.
+        let mut shift = |key: Key| -> Result<Option<Key>, SheetError> {
+            let Key::Unicode(character) = key else {
+                return Ok(Some(key));
+            };
+            match (layout::transpose(character, steps), policy) {
+                ((shifted, false), _) => Ok(Some(Key::Unicode(shifted))),
+                (_, ClampPolicy::Error) => Err(SheetError::OffKeyboard(character)),
+                ((shifted, true), ClampPolicy::Nearest) => {
+                    adjusted += 1;
+                    Ok(Some(Key::Unicode(shifted)))
+                }
+                (_, ClampPolicy::Drop) => {
+                    adjusted += 1;
+                    Ok(None)
+                }
+            }
+        };
+
+        let mut tokens = Vec::with_capacity(self.tokens.len());
+        for token in &self.tokens {
+            let rest = |length: &Option<f64>| Token::Rest(length.unwrap_or(1.0));
+            tokens.extend(match token {
+                Token::Single(key, length) => Some(match shift(*key)? {
+                    Some(key) => Token::Single(key, *length),
+                    None => rest(length),
+                }),
+                Token::Tied(key, length) => Some(match shift(*key)? {
+                    Some(key) => Token::Tied(key, *length),
+                    None => rest(length),
+                }),
+                Token::Hold(key) => Some(shift(*key)?.map_or(Token::Rest(1.0), Token::Hold)),
+                // The hold was dropped too, so there is nothing to release.
+                Token::ReleaseHold(key) => shift(*key)?.map(Token::ReleaseHold),
+                Token::Many(keys, length) => {
+                    let keys: Chord = keys
+                        .iter()
+                        .filter_map(|&key| shift(key).transpose())
+                        .collect::<Result<_, _>>()?;
+                    Some(match keys.is_empty() {
+                        true => rest(length),
+                        false => Token::Many(keys, *length),
+                    })
+                }
+                Token::ManyFast(keys) => {
+                    let keys: Chord = keys
+                        .iter()
+                        .filter_map(|&key| shift(key).transpose())
+                        .collect::<Result<_, _>>()?;
+                    Some(match keys.is_empty() {
+                        true => Token::Rest(1.0),
+                        false => Token::ManyFast(keys),
+                    })
+                }
+                Token::ShortPause
+                | Token::ShortPauses(_)
+                | Token::Pause
+                | Token::LongPause
+                | Token::Rest(_)
+                | Token::TempoStart(_)
+                | Token::TempoEnd => Some(token.clone()),
+            });
+        }
+
+        self.tokens = tokens;
+        Ok(adjusted)
+    }
+}
+
 pub struct PauseDistribution {
     pub short: f64,
     pub standard: f64,
     pub long: f64,
     pub pause_ratio: f64,
     pub many_fast_proportion: f64,
+    /// Seconds of silence between the notes of a fast arpeggio, on top of their share.
+    pub many_fast_gap: f64,
+    /// How many times its length a chord's keys stay down; `1.0` releases them with the chord.
+    pub chord_hold: f64,
+}
+
+impl PauseDistribution {
+    /// Rescales `short`, `standard` and `long` to add up to 1.0, so they can be given as weights
+    /// like `2, 3, 5`. Weights that add up to zero are left as they are, and
+    /// [`calculate_token_durations`] rejects them.
+    pub fn normalized(self) -> PauseDistribution {
+        let total = self.short + self.standard + self.long;
+        if total == 0.0 || !total.is_finite() {
+            return self;
+        }
+
+        PauseDistribution {
+            short: self.short / total,
+            standard: self.standard / total,
+            long: self.long / total,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug)]
+pub enum SheetError {
+    UnmatchedBracket(Position),
+    CloseWithoutOpen(Position),
+    MissingLength,
+    InvalidLengthFormat(&'static str),
+    InvalidDefine(Position),
+    InvalidDistribution(&'static str),
+    InvalidMidi(String),
+    InvalidJson(String),
+    UnknownKey(char, Position),
+    Read(io::Error),
+    NotText,
+    /// The extension of a file that no [`SheetParser`] is registered for.
+    UnknownFormat(String),
+    OutOfRange(Position),
+    /// A note that [`Sheet::transpose_with`] would move past the edge of the keyboard.
+    OffKeyboard(char),
+    InvalidRepetition(Position),
+    RecursiveDefine(String, Position),
+    InvalidTempo(&'static str),
+    InvalidSpaces,
+    InvalidRepetitionMode,
+    InvalidDuration(Position),
+    InvalidMarker(Position),
+    InvalidHold(Position),
+    InvalidTie(Position),
+    InvalidRest(Position),
+    InvalidTuplet(Position),
+    InvalidRepeat(Position),
+    DuplicateLabel(String, Position),
+    /// A `>>name` jump to a section that has not been labelled before it.
+    UnknownSection(String, Position),
+    /// A define that [`parse_reader`] reads after notes it would have changed.
+    LateDefine(String, Position),
+    NoNotes,
+}
+
+impl fmt::Display for SheetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SheetError::UnmatchedBracket(pos) => write!(f, "unmatched '[' at {}", pos),
+            SheetError::CloseWithoutOpen(pos) => write!(f, "unexpected ']' at {}", pos),
+            SheetError::MissingLength => write!(f, "Sheet length must be defined"),
+            SheetError::InvalidLengthFormat(reason) => write!(f, "{}", reason),
+            SheetError::InvalidDefine(pos) => {
+                write!(f, "Defines must be a name and value pair (at {})", pos)
+            }
+            SheetError::InvalidDistribution(reason) => write!(f, "{}", reason),
+            SheetError::InvalidMidi(reason) => write!(f, "Invalid MIDI file: {}", reason),
+            SheetError::InvalidJson(reason) => write!(f, "Invalid JSON sheet: {}", reason),
+            SheetError::UnknownKey(character, pos) => {
+                write!(f, "'{}' at {} is not a VirtualPiano key", character, pos)
+            }
+            SheetError::Read(err) => write!(f, "Could not read the sheet: {}", err),
+            SheetError::NotText => write!(f, "Sheet is not UTF-8 text"),
+            SheetError::UnknownFormat(extension) if extension.is_empty() => {
+                write!(f, "Sheet files need an extension such as .txt")
+            }
+            SheetError::UnknownFormat(extension) => {
+                write!(f, "No parser for '.{}' sheets", extension)
+            }
+            SheetError::InvalidRepetition(pos) => write!(
+                f,
+                "'*N' must follow a note or chord and repeat it at least once at {}",
+                pos
+            ),
+            SheetError::RecursiveDefine(name, pos) => {
+                write!(f, "define '{}' refers to itself at {}", name, pos)
+            }
+            SheetError::InvalidTempo(reason) => write!(f, "{}", reason),
+            SheetError::InvalidSpaces => write!(f, "#spaces must be 'single' or 'scaled'"),
+            SheetError::InvalidRepetitionMode => write!(f, "#repetition must be 'on' or 'off'"),
+            SheetError::InvalidDuration(pos) => write!(
+                f,
+                "':' must follow a note or chord and give a positive length factor of at most {} at {}",
+                MAX_FACTOR, pos
+            ),
+            SheetError::InvalidMarker(pos) => write!(
+                f,
+                "expected {{rit}}, {{accel}} with a target of at most {} or a closing {{end}} marker at {}",
+                MAX_FACTOR, pos
+            ),
+            SheetError::InvalidHold(pos) => write!(
+                f,
+                "'~' must follow a note to hold it, or come before a held note to release it at {}",
+                pos
+            ),
+            SheetError::InvalidTie(pos) => write!(
+                f,
+                "'_' must follow a note and tie it into a following note on the same key at {}",
+                pos
+            ),
+            SheetError::InvalidRest(pos) => {
+                write!(
+                    f,
+                    "'.' must give a positive rest length of at most {} notes at {}",
+                    MAX_FACTOR, pos
+                )
+            }
+            SheetError::InvalidTuplet(pos) => write!(
+                f,
+                "'{{N ...}}' needs a count of at least 2 and only notes, chords or rests before its '}}' at {}",
+                pos
+            ),
+            SheetError::InvalidRepeat(pos) => write!(
+                f,
+                "'|:' and ':|' must come in unnested pairs and play at least once at {}",
+                pos
+            ),
+            SheetError::DuplicateLabel(name, pos) => {
+                write!(f, "label '{}' is already defined at {}", name, pos)
+            }
+            SheetError::UnknownSection(name, pos) => {
+                write!(f, "no section '{}' to jump to at {}", name, pos)
+            }
+            SheetError::LateDefine(name, pos) => write!(
+                f,
+                "'#{}' must be defined before the notes that use it when streaming, at {}",
+                name, pos
+            ),
+            SheetError::NoNotes => write!(f, "Sheet has no notes to play"),
+            SheetError::OutOfRange(pos) => {
+                write!(f, "note shifted past the edge of the keyboard at {}", pos)
+            }
+            SheetError::OffKeyboard(character) => {
+                write!(
+                    f,
+                    "transposing '{}' moves it past the edge of the keyboard",
+                    character
+                )
+            }
+        }
+    }
+}
+
+impl Error for SheetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SheetError::Read(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// A problem in a sheet that doesn't stop it from being played.
+#[derive(Debug)]
+pub enum SheetWarning {
+    /// A non-ASCII character that no VirtualPiano key types, which is left out.
+    UnknownCharacter(char, Position),
+    /// A note on a character that isn't one of VirtualPiano's [`layout::KEYS`], which is still
+    /// pressed but plays nothing.
+    UnknownKey(char, Position),
+}
+
+impl fmt::Display for SheetWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SheetWarning::UnknownCharacter(character, pos) => {
+                write!(f, "skipped unknown character '{}' at {}", character, pos)
+            }
+            SheetWarning::UnknownKey(character, pos) => {
+                write!(f, "'{}' at {} is not a VirtualPiano key", character, pos)
+            }
+        }
+    }
 }
 
 pub fn calculate_token_durations(
     multiplier: f64,
     pause_distribution: &PauseDistribution,
-) -> Result<TokenDurations, String> {
+) -> Result<TokenDurations, SheetError> {
     if pause_distribution.pause_ratio <= 0.0 {
-        return Err("Note-pause ratio must be greater than zero.".to_string());
+        return Err(SheetError::InvalidDistribution(
+            "Note-pause ratio must be greater than zero.",
+        ));
     }
 
     let total_pause_distribution =
         pause_distribution.short + pause_distribution.standard + pause_distribution.long;
-    if total_pause_distribution != 1.0 {
-        return Err("Pause distribution percentages must add up to 1.0".to_string());
+    // Allow for rounding, since e.g. 0.2 + 0.7 + 0.1 is not exactly 1.0 in floating point.
+    if (total_pause_distribution - 1.0).abs() >= 1e-9 {
+        return Err(SheetError::InvalidDistribution(
+            "Pause distribution percentages must add up to 1.0",
+        ));
     }
     if pause_distribution.many_fast_proportion < 0.0
         || pause_distribution.many_fast_proportion > 1.0
     {
-        return Err("many_fast_proportion must be between 0.0 and 1.0".to_string());
+        return Err(SheetError::InvalidDistribution(
+            "many_fast_proportion must be between 0.0 and 1.0",
+        ));
+    }
+    if pause_distribution.chord_hold <= 0.0 || !pause_distribution.chord_hold.is_finite() {
+        return Err(SheetError::InvalidDistribution(
+            "chord_hold must be greater than zero",
+        ));
     }
 
     let note_proportion = pause_distribution.pause_ratio / (pause_distribution.pause_ratio + 1.0);
@@ -71,123 +680,1821 @@ pub fn calculate_token_durations(
     let many_fast = pause_distribution.many_fast_proportion * multiplier;
 
     Ok(TokenDurations {
-        short_pause: pause_time * pause_distribution.short,
-        pause: pause_time * pause_distribution.standard,
-        long_pause: pause_time * pause_distribution.long,
-
-        single,
-        many_fast,
+        chord_hold: pause_distribution.chord_hold,
+        ..TokenDurations::from_secs(
+            pause_time * pause_distribution.short,
+            pause_time * pause_distribution.standard,
+            pause_time * pause_distribution.long,
+            single,
+            many_fast,
+            pause_distribution.many_fast_gap,
+        )?
     })
 }
 
-fn parse_tokens(output: &mut Vec<Token>, input: &str) -> Result<(), String> {
-    let chars = input.chars();
+/// Durations that play `sheet` at its `#bpm`, a note to a beat, or otherwise fill its declared
+/// length. Pauses take their share first and the notes are stretched over the rest, so a sheet
+/// with many pauses gets shorter notes.
+pub fn calculate_token_durations_for(
+    sheet: &Sheet,
+    pause_distribution: &PauseDistribution,
+) -> Result<TokenDurations, SheetError> {
+    if sheet.note_count() == 0 {
+        return Err(SheetError::NoNotes);
+    }
+
+    // Pauses don't depend on the multiplier and notes grow in proportion to it, so playing the
+    // sheet at two multipliers gives the one that plays it for exactly its length.
+    let unit = calculate_token_durations(1.0, pause_distribution)?;
+    let multiplier = match sheet.header.bpm {
+        Some(bpm) => 60.0 / bpm / unit.single.as_secs_f64(),
+        None => {
+            let fixed = sheet
+                .estimated_duration(&calculate_token_durations(0.0, pause_distribution)?)
+                .as_secs_f64();
+            let per_multiplier = sheet.estimated_duration(&unit).as_secs_f64() - fixed;
+            ((sheet.header.length - fixed) / per_multiplier).max(0.0)
+        }
+    };
+    calculate_token_durations(multiplier, pause_distribution)
+}
+
+/// Maps a note character to its key, `octave` octaves away along the VirtualPiano layout.
+fn note_key(character: char, octave: i32, position: Position) -> Result<Key, SheetError> {
+    if octave == 0 {
+        return Ok(Key::Unicode(character));
+    }
+
+    match layout::transpose(character, octave * 12) {
+        (_, true) => Err(SheetError::OutOfRange(position)),
+        (shifted, false) => Ok(Key::Unicode(shifted)),
+    }
+}
+
+const DEFAULT_RITARDANDO: f64 = 1.5;
+
+/// The largest length factor, rest or tempo target a sheet can give. Much larger ones would
+/// overflow a `Duration` once they are multiplied out.
+const MAX_FACTOR: f64 = 1000.0;
+
+/// How much a `{N ...}` tuplet scales its notes: `N` of them take the time of the largest power
+/// of two below `N`, so a triplet plays three notes in the time of two.
+fn tuplet_ratio(count: u32) -> f64 {
+    let normal = 1u32 << (u32::BITS - 1 - (count - 1).leading_zeros());
+    normal as f64 / count as f64
+}
+
+/// State that carries over from one line to the next.
+#[derive(Default)]
+struct ParseState {
+    /// Keys to send in place of the sheet's characters, for keyboards laid out differently.
+    remap: HashMap<char, Key>,
+    /// Whether a note that isn't a VirtualPiano key is an error rather than a warning.
+    strict_keys: bool,
+    warnings: Vec<SheetWarning>,
+    /// Whether a run of spaces is one pause (`#spaces scaled`) rather than a pause per space.
+    scaled_spaces: bool,
+    /// Whether `*N` repeats the note before it (`#repetition on`) rather than being a `*` key.
+    repetition: bool,
+    /// Octave shift set by `<`/`>` markers.
+    octave: i32,
+    /// Whether a `{rit}`/`{accel}` span is waiting for its `{end}`.
+    in_tempo_span: bool,
+    /// Keys held with `a~` that have not been released yet.
+    held: Vec<Key>,
+    /// The key of a `a_` tie still waiting for its next note, and where the `_` is.
+    tie: Option<(Key, Position)>,
+    /// Index of the first token after an open `|:`, and where the marker is.
+    repeat_start: Option<(usize, Position)>,
+    /// Token ranges of the `@name` sections that have ended.
+    sections: HashMap<String, Range<usize>>,
+    /// The latest `@name` section, and its first token, until a label or jump ends it.
+    open_section: Option<(String, usize)>,
+}
+
+impl ParseState {
+    /// The key for a note character, after the octave shift and then the remapping.
+    fn key(&self, character: char, position: Position) -> Result<Key, SheetError> {
+        match note_key(character, self.octave, position)? {
+            Key::Unicode(character) => Ok(self
+                .remap
+                .get(&character)
+                .copied()
+                .unwrap_or(Key::Unicode(character))),
+            key => Ok(key),
+        }
+    }
+
+    /// The key for a note, checking that it is one VirtualPiano has. Remapped characters are
+    /// taken to be keys, since the remapping is there to type them.
+    fn note(&mut self, character: char, position: Position) -> Result<Key, SheetError> {
+        let key = self.key(character, position)?;
+        if let Ok(Key::Unicode(shifted)) = note_key(character, self.octave, position)
+            && layout::index_of(shifted).is_none()
+            && !self.remap.contains_key(&shifted)
+        {
+            match self.strict_keys {
+                true => return Err(SheetError::UnknownKey(shifted, position)),
+                false => self
+                    .warnings
+                    .push(SheetWarning::UnknownKey(shifted, position)),
+            }
+        }
+        Ok(key)
+    }
+
+    fn close_section(&mut self, end: usize) {
+        if let Some((name, start)) = self.open_section.take() {
+            self.sections.insert(name, start..end);
+        }
+    }
+}
+
+fn parse_marker(
+    marker: &str,
+    state: &mut ParseState,
+    position: Position,
+) -> Result<Token, SheetError> {
+    let (name, target) = match marker.trim().split_once(' ') {
+        None => (marker.trim(), None),
+        Some((name, target)) => match parse_factor(target) {
+            None => return Err(SheetError::InvalidMarker(position)),
+            target => (name, target),
+        },
+    };
+
+    let token = match (name, target) {
+        ("rit", target) => Token::TempoStart(target.unwrap_or(DEFAULT_RITARDANDO)),
+        ("accel", target) => Token::TempoStart(target.unwrap_or(1.0 / DEFAULT_RITARDANDO)),
+        ("end", None) if state.in_tempo_span => Token::TempoEnd,
+        _ => return Err(SheetError::InvalidMarker(position)),
+    };
+
+    match token {
+        Token::TempoStart(_) if state.in_tempo_span => Err(SheetError::InvalidMarker(position)),
+        Token::TempoStart(_) => {
+            state.in_tempo_span = true;
+            Ok(token)
+        }
+        _ => {
+            state.in_tempo_span = false;
+            Ok(token)
+        }
+    }
+}
+
+fn parse_tokens(
+    output: &mut Vec<Token>,
+    input: &str,
+    line: usize,
+    state: &mut ParseState,
+) -> Result<(), SheetError> {
+    let mut chars = input.chars().enumerate().peekable();
 
     let mut in_many = false;
     let mut in_many_fast = false;
-    let mut group: Option<Vec<Key>> = None;
-    for character in chars {
+    let mut group: Option<Chord> = None;
+    let mut group_start = Position { line, column: 0 };
+    // First token of an open `{N ...}` tuplet, its ratio, and where it starts.
+    let mut tuplet: Option<(usize, f64, Position)> = None;
+    // Whether the last token pushed was a note or chord that `*N` may repeat.
+    let mut last_note = false;
+    while let Some((index, character)) = chars.next() {
+        let position = Position {
+            line,
+            column: index + 1,
+        };
+        let was_note = last_note;
+        last_note = false;
         match character {
+            '[' if state.tie.is_some() => return Err(SheetError::InvalidTie(position)),
             '[' => {
                 in_many = true;
-                group = Some(Vec::new());
+                group = Some(Chord::new());
+                group_start = position;
             }
             ']' => {
                 if let Some(keys) = group.take() {
                     if in_many_fast {
                         output.push(Token::ManyFast(keys));
                     } else if in_many {
-                        output.push(Token::Many(keys));
+                        output.push(Token::Many(keys, None));
                     } else {
-                        return Err("Attempted to close while not open".to_string());
+                        return Err(SheetError::CloseWithoutOpen(position));
                     }
                 } else {
-                    return Err("Attempted to close invalid key block".to_string());
+                    return Err(SheetError::CloseWithoutOpen(position));
                 }
                 in_many = false;
                 in_many_fast = false;
+                last_note = true;
+            }
+            // `*` is also a key on VirtualPiano, so it only repeats when the sheet asks for it and a
+            // count follows.
+            '*' if state.repetition
+                && group.is_none()
+                && chars.peek().is_some_and(|(_, c)| c.is_ascii_digit()) =>
+            {
+                if !was_note {
+                    return Err(SheetError::InvalidRepetition(position));
+                }
+
+                let mut count = String::new();
+                while let Some((_, digit)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                    count.push(digit);
+                }
+                let count: usize = match count.parse() {
+                    Ok(count) if count > 0 => count,
+                    _ => return Err(SheetError::InvalidRepetition(position)),
+                };
+
+                let repeated = output.last().unwrap().clone();
+                output.extend(std::iter::repeat_n(repeated, count - 1));
+            }
+            '|' if group.is_none() && chars.peek().is_some_and(|(_, c)| *c == ':') => {
+                chars.next();
+                if state.repeat_start.is_some() {
+                    return Err(SheetError::InvalidRepeat(position));
+                }
+                state.repeat_start = Some((output.len(), position));
+            }
+            ':' if group.is_none() && chars.peek().is_some_and(|(_, c)| *c == '|') => {
+                chars.next();
+                let Some((start, _)) = state.repeat_start.take() else {
+                    return Err(SheetError::InvalidRepeat(position));
+                };
+
+                // `x` is also a key, so it only gives the count when digits follow.
+                let mut ahead = chars.clone();
+                let mut count: usize = 2;
+                if ahead.next().is_some_and(|(_, c)| c == 'x')
+                    && ahead.peek().is_some_and(|(_, c)| c.is_ascii_digit())
+                {
+                    chars.next();
+                    let mut digits = String::new();
+                    while let Some((_, digit)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                        digits.push(digit);
+                    }
+                    count = match digits.parse() {
+                        Ok(count) if count > 0 => count,
+                        _ => return Err(SheetError::InvalidRepeat(position)),
+                    };
+                }
+
+                let section = output[start..].to_vec();
+                for _ in 1..count {
+                    output.extend_from_slice(&section);
+                }
+            }
+            ':' if group.is_none() => {
+                let factor = parse_factor(&take_number(&mut chars));
+
+                match (output.last_mut(), factor) {
+                    (Some(Token::Single(_, length) | Token::Many(_, length)), Some(factor))
+                        if was_note =>
+                    {
+                        *length = Some(factor);
+                    }
+                    _ => return Err(SheetError::InvalidDuration(position)),
+                }
+                // The lengthened note can still be repeated with `*N`.
+                last_note = true;
+            }
+            '~' if group.is_none() => {
+                if was_note && let Some(Token::Single(key, None)) = output.last() {
+                    let key = *key;
+                    output.pop();
+                    output.push(Token::Hold(key));
+                    state.held.push(key);
+                    continue;
+                }
+
+                let character = match chars.next() {
+                    Some((_, '\\')) => chars.next().map_or('\\', |(_, escaped)| escaped),
+                    Some((_, character)) => character,
+                    None => return Err(SheetError::InvalidHold(position)),
+                };
+                let key = state.key(character, position)?;
+                let Some(held) = state.held.iter().position(|held| *held == key) else {
+                    return Err(SheetError::InvalidHold(position));
+                };
+                state.held.remove(held);
+                output.push(Token::ReleaseHold(key));
+            }
+            '.' if group.is_none() => match parse_factor(&take_number(&mut chars)) {
+                Some(notes) => output.push(Token::Rest(notes)),
+                None => return Err(SheetError::InvalidRest(position)),
+            },
+            '_' if group.is_none() => {
+                let Some(Token::Single(key, length)) = output.last().filter(|_| was_note).cloned()
+                else {
+                    return Err(SheetError::InvalidTie(position));
+                };
+                *output.last_mut().unwrap() = Token::Tied(key, length);
+                state.tie = Some((key, position));
+            }
+            // `||:` is a pause before a `|:` repeat start rather than a long pause and a `:`.
+            '|' if group.is_none() && chars.peek().is_some_and(|(_, c)| *c == '|') => {
+                let mut ahead = chars.clone();
+                ahead.next();
+                if ahead.peek().is_some_and(|(_, c)| *c == ':') {
+                    output.push(Token::Pause);
+                } else {
+                    chars.next();
+                    output.push(Token::LongPause);
+                }
             }
             '|' => output.push(Token::Pause),
+            '<' => state.octave -= 1,
+            '>' => state.octave += 1,
+            '{' if group.is_none() && chars.peek().is_some_and(|(_, c)| c.is_ascii_digit()) => {
+                let mut count = String::new();
+                while let Some((_, digit)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                    count.push(digit);
+                }
+                let count = match count.parse::<u32>() {
+                    Ok(count) if count >= 2 && tuplet.is_none() => count,
+                    _ => return Err(SheetError::InvalidTuplet(position)),
+                };
+                if chars.next_if(|(_, c)| *c == ' ').is_none() {
+                    return Err(SheetError::InvalidTuplet(position));
+                }
+                tuplet = Some((output.len(), tuplet_ratio(count), position));
+            }
+            '}' if group.is_none() && tuplet.is_some() => {
+                let (start, ratio, _) = tuplet.take().unwrap();
+                for token in &mut output[start..] {
+                    match token {
+                        Token::Single(_, length)
+                        | Token::Tied(_, length)
+                        | Token::Many(_, length) => {
+                            *length = Some(length.unwrap_or(1.0) * ratio);
+                        }
+                        Token::Rest(notes) => *notes *= ratio,
+                        _ => return Err(SheetError::InvalidTuplet(position)),
+                    }
+                }
+            }
+            '{' if group.is_none() => {
+                if tuplet.is_some() {
+                    return Err(SheetError::InvalidTuplet(position));
+                }
+                let mut marker = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '}')) => break,
+                        Some((_, character)) => marker.push(character),
+                        None => return Err(SheetError::InvalidMarker(position)),
+                    }
+                }
+                output.push(parse_marker(&marker, state, position)?);
+            }
+            // Tabs only lay out the sheet, as no VirtualPiano key is typed with one.
+            '\t' => {}
             ' ' => {
                 if in_many {
                     in_many_fast = true;
+                } else if state.scaled_spaces {
+                    let mut spaces = 1;
+                    while chars.next_if(|(_, c)| *c == ' ').is_some() {
+                        spaces += 1;
+                    }
+                    output.push(match spaces {
+                        1 => Token::ShortPause,
+                        spaces => Token::ShortPauses(spaces),
+                    });
                 } else {
                     output.push(Token::ShortPause);
                 }
             }
+            _ if !character.is_ascii() && !state.remap.contains_key(&character) => {
+                state
+                    .warnings
+                    .push(SheetWarning::UnknownCharacter(character, position));
+            }
             _ => {
+                // A backslash makes the next character a note, even if it is normally syntax.
+                let character = match character {
+                    '\\' => chars.next().map_or('\\', |(_, escaped)| escaped),
+                    _ => character,
+                };
+                let key = state.note(character, position)?;
                 if let Some(keys) = &mut group {
-                    keys.push(Key::Unicode(character));
+                    keys.push(key);
                 } else {
-                    output.push(Token::Single(Key::Unicode(character)));
+                    if let Some((tied, tie_position)) = state.tie.take()
+                        && tied != key
+                    {
+                        return Err(SheetError::InvalidTie(tie_position));
+                    }
+                    output.push(Token::Single(key, None));
+                    last_note = true;
                 }
             }
         }
     }
 
+    if group.is_some() {
+        return Err(SheetError::UnmatchedBracket(group_start));
+    }
+    if let Some((_, _, position)) = tuplet {
+        return Err(SheetError::InvalidTuplet(position));
+    }
+
     Ok(())
 }
 
-pub fn parse_sheet(input: &str) -> Result<Sheet, String> {
-    let mut tokens: Vec<Token> = Vec::new();
-    let mut defines: HashMap<&str, &str> = HashMap::new();
+/// Takes the digits of a number off the front of `chars`, with at most one `.`, so that in
+/// `a:2..5` the factor `2.` is followed by a `.5` rest.
+fn take_number(chars: &mut Peekable<Enumerate<Chars>>) -> String {
+    let mut number = String::new();
+    while let Some((_, character)) =
+        chars.next_if(|(_, c)| c.is_ascii_digit() || (*c == '.' && !number.contains('.')))
+    {
+        number.push(character);
+    }
+    number
+}
 
-    let lines = input.lines();
+/// The name in a `@name` or `>>name` line, if `line` is only that.
+fn section_name<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    line.trim()
+        .strip_prefix(prefix)
+        .filter(|name| !name.is_empty())
+        .filter(|name| {
+            name.chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        })
+}
 
-    let mut last_line_empty = false;
-    for line in lines {
-        if line.is_empty() {
-            if !last_line_empty {
-                tokens.push(Token::LongPause);
+/// Copies the tokens of the section labelled `name` onto the end of `output`. Only sections that
+/// come earlier in the sheet can be jumped to, so a jump can never lead back to itself.
+fn jump_to(
+    output: &mut Vec<Token>,
+    state: &mut ParseState,
+    name: &str,
+    position: Position,
+) -> Result<(), SheetError> {
+    let open = state
+        .open_section
+        .as_ref()
+        .is_some_and(|(open, _)| open == name);
+    if !open && !state.sections.contains_key(name) {
+        return Err(SheetError::UnknownSection(name.to_string(), position));
+    }
+
+    state.close_section(output.len());
+    let section = output[state.sections[name].clone()].to_vec();
+    output.extend(section);
+    Ok(())
+}
+
+/// Removes a trailing `//` comment, along with the whitespace before it. Slashes inside a `[...]`
+/// group or escaped with a backslash are left alone.
+fn strip_comment(line: &str) -> &str {
+    let mut chars = line.char_indices();
+    let mut in_group = false;
+    let mut previous_slash = false;
+    while let Some((index, character)) = chars.next() {
+        match character {
+            '\\' => {
+                chars.next();
+                previous_slash = false;
+                continue;
             }
-            last_line_empty = true;
-            continue;
-        } else {
-            last_line_empty = false;
+            '[' => in_group = true,
+            ']' => in_group = false,
+            '/' if !in_group && previous_slash => return line[..index - 1].trim_end(),
+            _ => {}
+        }
+        previous_slash = character == '/' && !in_group;
+    }
+
+    line
+}
+
+/// Replaces each `$name` with the value of `#name`, expanding nested references. `$` is also a
+/// key, so it is kept as a note when no define matches; the longest matching name wins.
+/// `expanding` holds the names currently being expanded, to catch recursive defines, and
+/// `references` collects the word after each `$`, including those in the values expanded.
+fn expand_defines<'a>(
+    line: &str,
+    line_number: usize,
+    defines: &'a HashMap<String, String>,
+    expanding: &mut Vec<&'a str>,
+    mut references: Option<&mut HashSet<String>>,
+) -> Result<String, SheetError> {
+    let mut output = String::with_capacity(line.len());
+    let mut chars = line.char_indices();
+    while let Some((index, character)) = chars.next() {
+        match character {
+            '\\' => {
+                output.push(character);
+                if let Some((_, escaped)) = chars.next() {
+                    output.push(escaped);
+                }
+            }
+            '$' => {
+                let rest = &line[index + 1..];
+                if let Some(references) = references.as_deref_mut() {
+                    // Define names end at the first space, so that is as far as a name can reach.
+                    let word = rest.split(' ').next().unwrap_or_default();
+                    if !references.contains(word) {
+                        references.insert(word.to_string());
+                    }
+                }
+                let Some((name, value)) = defines
+                    .iter()
+                    .filter(|(name, _)| name.len() > 1 && rest.starts_with(&name[1..]))
+                    .max_by_key(|(name, _)| name.len())
+                else {
+                    output.push(character);
+                    continue;
+                };
+
+                if expanding.contains(&name.as_str()) {
+                    return Err(SheetError::RecursiveDefine(
+                        name[1..].to_string(),
+                        Position {
+                            line: line_number,
+                            column: line[..index].chars().count() + 1,
+                        },
+                    ));
+                }
+                expanding.push(name);
+                output.push_str(&expand_defines(
+                    value,
+                    line_number,
+                    defines,
+                    expanding,
+                    references.as_deref_mut(),
+                )?);
+                expanding.pop();
+
+                for _ in name[1..].chars() {
+                    chars.next();
+                }
+            }
+            _ => output.push(character),
+        }
+    }
+
+    Ok(output)
+}
+
+/// Reads a `#length` as seconds. Accepts `mm:ss`, `h:mm:ss`, bare seconds (`220`), and units
+/// (`3m40s`, `1h`, `90s`).
+fn parse_length(length: &str) -> Result<f64, SheetError> {
+    let length = length.trim();
+    if let Ok(secs) = length.parse::<f64>() {
+        return checked_length(secs);
+    }
+    if length.ends_with(['h', 'm', 's']) {
+        return parse_length_units(length);
+    }
+
+    let parts: Vec<&str> = length.split(':').collect();
+    let (hours, mins, secs) = match parts[..] {
+        [mins, secs] => ("0", mins, secs),
+        [hours, mins, secs] => (hours, mins, secs),
+        _ => {
+            return Err(SheetError::InvalidLengthFormat(
+                "Invalid sheet length format, expected seconds, m:ss, h:mm:ss or 3m40s",
+            ));
+        }
+    };
+    let hours = match hours.parse::<f64>() {
+        Ok(x) if x >= 0.0 => x,
+        _ => {
+            return Err(SheetError::InvalidLengthFormat(
+                "Invalid sheet length hours",
+            ));
         }
+    };
+    let mins = match mins.parse::<f64>() {
+        Ok(x) if x >= 0.0 => x,
+        _ => {
+            return Err(SheetError::InvalidLengthFormat(
+                "Invalid sheet length minutes",
+            ));
+        }
+    };
+    let secs = match secs.parse::<f64>() {
+        Ok(x) if x >= 0.0 => x,
+        _ => {
+            return Err(SheetError::InvalidLengthFormat(
+                "Invalid sheet length seconds",
+            ));
+        }
+    };
+    checked_length(hours * 3600.0 + mins * 60.0 + secs)
+}
+
+/// Refuses lengths that no sheet can have: negative ones, and ones too large to be finite.
+fn checked_length(secs: f64) -> Result<f64, SheetError> {
+    match secs.is_finite() && secs >= 0.0 {
+        true => Ok(secs),
+        false => Err(SheetError::InvalidLengthFormat(
+            "Sheet length must be a finite number of seconds, not negative",
+        )),
+    }
+}
+
+/// Reads a length such as `3m40s`: numbers each followed by `h`, `m` or `s`, in that order.
+fn parse_length_units(length: &str) -> Result<f64, SheetError> {
+    const UNITS: [(char, f64); 3] = [('h', 3600.0), ('m', 60.0), ('s', 1.0)];
+    let invalid =
+        || SheetError::InvalidLengthFormat("Invalid sheet length units, expected e.g. 3m40s");
+
+    let mut total = 0.0;
+    let mut rest = length;
+    let mut units = UNITS.iter();
+    while !rest.is_empty() {
+        let end = rest.find(|c: char| c.is_alphabetic()).ok_or_else(invalid)?;
+        let value = rest[..end]
+            .parse::<f64>()
+            .ok()
+            .filter(|value| *value >= 0.0)
+            .ok_or_else(invalid)?;
+        let unit = rest[end..].chars().next().unwrap();
+        // Units can be left out but not repeated or reordered, so `1m2h` is refused.
+        let &(_, scale) = units.find(|(name, _)| *name == unit).ok_or_else(invalid)?;
+        total += value * scale;
+        rest = &rest[end + unit.len_utf8()..];
+    }
+
+    checked_length(total)
+}
+
+fn parse_positive(value: &str) -> Option<f64> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|&x| x > 0.0 && x.is_finite())
+}
+
+/// Reads a length factor, rest or tempo target, which can be at most [`MAX_FACTOR`].
+fn parse_factor(value: &str) -> Option<f64> {
+    parse_positive(value).filter(|&x| x <= MAX_FACTOR)
+}
+
+/// Works out how much each token's durations are stretched by `{rit}`/`{accel}` spans. Inside a
+/// span the factor moves linearly from `1.0` towards the span's target, reaching it on the last
+/// token before `TempoEnd`; an unclosed span runs to the end of the sheet.
+fn tempo_factors(tokens: &[Token]) -> TempoFactors<'_> {
+    TempoFactors {
+        tokens,
+        index: 0,
+        span: None,
+    }
+}
+
+/// The iterator returned by [`tempo_factors`].
+#[derive(Clone)]
+struct TempoFactors<'a> {
+    tokens: &'a [Token],
+    index: usize,
+    /// The target of the span being read, and the tokens inside it.
+    span: Option<(f64, Range<usize>)>,
+}
+
+impl Iterator for TempoFactors<'_> {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        let index = self.index;
+        let token = self.tokens.get(index)?;
+        self.index += 1;
+
+        if let Some((target, span)) = &self.span
+            && span.contains(&index)
+        {
+            let step = index - span.start + 1;
+            return Some(1.0 + (target - 1.0) * step as f64 / span.len() as f64);
+        }
+        if let Token::TempoStart(target) = *token {
+            let end = self.tokens[index..]
+                .iter()
+                .position(|token| matches!(token, Token::TempoEnd))
+                .map_or(self.tokens.len(), |offset| index + offset);
+            self.span = Some((target, index + 1..end));
+        }
+        Some(1.0)
+    }
 
-        if line.chars().next().unwrap() == '#' {
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.tokens.len() - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+fn count_notes(tokens: &[Token]) -> usize {
+    tokens
+        .iter()
+        .filter(|token| {
+            matches!(
+                token,
+                Token::Single(..)
+                    | Token::Tied(..)
+                    | Token::Many(..)
+                    | Token::ManyFast(_)
+                    | Token::Hold(_)
+            )
+        })
+        .count()
+}
+
+/// Parses a whole text sheet. It reads the same as [`parse_reader`] would, except that the defines
+/// are all collected before the notes, so a note can use one declared further down and
+/// `#spaces` or `#repetition` can come anywhere.
+pub fn parse_sheet(input: &str) -> Result<Sheet, SheetError> {
+    parse_sheet_remapped(input, &HashMap::new())
+}
+
+/// Parses a sheet like [`parse_sheet`], sending the key in `remap` for each note character that
+/// has one. Characters without an entry are sent as they are.
+pub fn parse_sheet_remapped(input: &str, remap: &HashMap<char, Key>) -> Result<Sheet, SheetError> {
+    parse_sheet_with_warnings(input, remap).map(|(sheet, _)| sheet)
+}
+
+/// Reads typographic punctuation that editors substitute in as the ASCII character it stands for.
+fn ascii_punctuation(character: char) -> char {
+    match character {
+        '\u{2018}' | '\u{2019}' | '\u{201A}' => '\'',
+        '\u{201C}' | '\u{201D}' | '\u{201E}' => '"',
+        '\u{2013}' | '\u{2014}' => '-',
+        '\u{00A0}' => ' ',
+        _ => character,
+    }
+}
+
+// `lines` only drops a `\r` that comes before a `\n`, which misses one on the last line.
+fn sheet_lines(input: &str) -> impl Iterator<Item = &str> + Clone {
+    input
+        .lines()
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+}
+
+/// The `#name value` lines of a sheet, keyed by `#name`.
+fn collect_defines(input: &str) -> Result<HashMap<&str, &str>, SheetError> {
+    let mut defines = HashMap::new();
+    for (index, line) in sheet_lines(input).enumerate() {
+        if line.starts_with('#') {
             match line.split_once(' ') {
-                None => return Err("Defines must be a name and value pair".to_string()),
+                None => {
+                    return Err(SheetError::InvalidDefine(Position {
+                        line: index + 1,
+                        column: 1,
+                    }));
+                }
                 Some((k, v)) => defines.insert(k, v),
             };
-            continue;
-        }
-
-        if let Err(x) = parse_tokens(&mut tokens, line) {
-            return Err(x);
         }
     }
+    Ok(defines)
+}
 
-    let length = match defines.get("#length") {
-        None => return Err("Sheet length must be defined".to_string()),
-        Some(&length) => match length.split_once(':') {
-            None => return Err("Invalid sheet length format".to_string()),
-            Some((mins, secs)) => {
-                let mins = match mins.parse::<f64>() {
-                    Ok(x) => x,
-                    Err(_) => return Err("Invalid sheet length minutes".to_string()),
-                };
-                let secs = match secs.parse::<f64>() {
-                    Ok(x) => x,
-                    Err(_) => return Err("Invalid sheet length seconds".to_string()),
-                };
-                mins * 60.0 + secs
-            }
-        },
+/// Builds the header from the defines. `notes` counts the notes of the sheet, which is only
+/// needed for the length of a `#bpm` sheet without `#beats`.
+fn parse_header(
+    defines: &HashMap<&str, &str>,
+    notes: impl FnOnce() -> Result<usize, SheetError>,
+) -> Result<Header, SheetError> {
+    let bpm = match defines.get("#bpm") {
+        None => None,
+        Some(bpm) => Some(
+            parse_positive(bpm)
+                .ok_or(SheetError::InvalidTempo("#bpm must be a positive number"))?,
+        ),
     };
 
-    let header = Header {
-        title: match defines.get("#title") {
-            None => None,
-            Some(&x) => Some(x.to_string()),
-        },
-        writer: match defines.get("#writer") {
-            None => None,
-            Some(&x) => Some(x.to_string()),
+    let length = match bpm {
+        Some(bpm) => {
+            let beats = match defines.get("#beats") {
+                None => notes()? as f64,
+                Some(beats) => parse_positive(beats)
+                    .ok_or(SheetError::InvalidTempo("#beats must be a positive number"))?,
+            };
+            beats * 60.0 / bpm
+        }
+        None => match defines.get("#length") {
+            None => return Err(SheetError::MissingLength),
+            Some(length) => parse_length(length)?,
         },
-        length,
     };
 
-    Ok(Sheet { tokens, header })
+    Ok(Header {
+        title: defines.get("#title").map(|x| x.to_string()),
+        writer: defines.get("#writer").map(|x| x.to_string()),
+        length,
+        bpm,
+        metadata: defines
+            .iter()
+            .map(|(name, value)| (name[1..].to_string(), value.to_string()))
+            .collect(),
+    })
+}
+
+/// Reads only the header of a text sheet, which is much quicker than [`parse_sheet`] since the
+/// notes are skipped. The one exception is a `#bpm` sheet without `#beats`, whose length comes
+/// from its notes, so it is parsed in full.
+pub fn parse_header_only(input: &str) -> Result<Header, SheetError> {
+    let input: String = input.nfc().map(ascii_punctuation).collect();
+    let defines = collect_defines(&input)?;
+    parse_header(&defines, || {
+        parse_sheet(&input).map(|sheet| count_notes(&sheet.tokens))
+    })
+}
+
+/// Parses a sheet like [`parse_sheet_remapped`], also returning the warnings about it.
+pub fn parse_sheet_with_warnings(
+    input: &str,
+    remap: &HashMap<char, Key>,
+) -> Result<(Sheet, Vec<SheetWarning>), SheetError> {
+    parse_sheet_checked(input, remap, KeyCheck::Lenient)
+}
+
+/// How the parser treats notes on characters that aren't VirtualPiano keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCheck {
+    /// Plays them anyway, with a [`SheetWarning::UnknownKey`] for each.
+    Lenient,
+    /// Fails with [`SheetError::UnknownKey`] at the first one.
+    Strict,
+}
+
+/// Whether `#spaces` makes a run of spaces one pause.
+fn scaled_spaces(spaces: Option<&str>) -> Result<bool, SheetError> {
+    match spaces.map(|spaces| spaces.trim()) {
+        None | Some("single") => Ok(false),
+        Some("scaled") => Ok(true),
+        Some(_) => Err(SheetError::InvalidSpaces),
+    }
+}
+
+/// Whether `#repetition` turns on `*N`.
+fn repetition(repetition: Option<&str>) -> Result<bool, SheetError> {
+    match repetition.map(|repetition| repetition.trim()) {
+        None | Some("off") => Ok(false),
+        Some("on") => Ok(true),
+        Some(_) => Err(SheetError::InvalidRepetitionMode),
+    }
+}
+
+/// Reads a normalized sheet one line at a time.
+struct LineParser {
+    tokens: Vec<Token>,
+    state: ParseState,
+    defines: HashMap<String, String>,
+    /// Whether defines are added as their lines are read, rather than all known up front.
+    streaming: bool,
+    /// The word after each `$` of the notes read so far, when streaming, so a define that
+    /// would have changed one of them can be caught.
+    references: HashSet<String>,
+    read_notes: bool,
+    last_line_empty: bool,
+    line_number: usize,
+}
+
+impl LineParser {
+    fn new(
+        remap: &HashMap<char, Key>,
+        check: KeyCheck,
+        defines: HashMap<String, String>,
+        streaming: bool,
+    ) -> Result<LineParser, SheetError> {
+        let state = ParseState {
+            remap: remap.clone(),
+            strict_keys: check == KeyCheck::Strict,
+            scaled_spaces: scaled_spaces(defines.get("#spaces").map(String::as_str))?,
+            repetition: repetition(defines.get("#repetition").map(String::as_str))?,
+            ..ParseState::default()
+        };
+        Ok(LineParser {
+            tokens: Vec::new(),
+            state,
+            defines,
+            streaming,
+            references: HashSet::new(),
+            read_notes: false,
+            last_line_empty: false,
+            line_number: 0,
+        })
+    }
+
+    fn define(&mut self, line: &str) -> Result<(), SheetError> {
+        let position = Position {
+            line: self.line_number,
+            column: 1,
+        };
+        let Some((name, value)) = line.split_once(' ') else {
+            return Err(SheetError::InvalidDefine(position));
+        };
+
+        let late = match name {
+            "#spaces" => {
+                let scaled = scaled_spaces(Some(value))?;
+                let late = self.read_notes && scaled != self.state.scaled_spaces;
+                self.state.scaled_spaces = scaled;
+                late
+            }
+            "#repetition" => {
+                let repetition = repetition(Some(value))?;
+                let late = self.read_notes && repetition != self.state.repetition;
+                self.state.repetition = repetition;
+                late
+            }
+            _ => {
+                name.len() > 1
+                    && self
+                        .references
+                        .iter()
+                        .any(|word| word.starts_with(&name[1..]))
+            }
+        };
+        if late {
+            return Err(SheetError::LateDefine(name[1..].to_string(), position));
+        }
+
+        self.defines.insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), SheetError> {
+        self.line_number += 1;
+        let line_number = self.line_number;
+        // Comment lines are invisible, so they neither cause nor interrupt a long pause.
+        if line.trim_start().starts_with("//") {
+            return Ok(());
+        }
+
+        if line.is_empty() {
+            if !self.last_line_empty {
+                self.tokens.push(Token::LongPause);
+            }
+            self.last_line_empty = true;
+            return Ok(());
+        } else {
+            self.last_line_empty = false;
+        }
+
+        if line.starts_with('#') {
+            if self.streaming {
+                self.define(line)?;
+            }
+            return Ok(());
+        }
+
+        let line = strip_comment(line);
+        let position = Position {
+            line: line_number,
+            column: line.len() - line.trim_start().len() + 1,
+        };
+        if let Some(name) = section_name(line, "@") {
+            self.state.close_section(self.tokens.len());
+            if self.state.sections.contains_key(name) {
+                return Err(SheetError::DuplicateLabel(name.to_string(), position));
+            }
+            self.state.open_section = Some((name.to_string(), self.tokens.len()));
+            return Ok(());
+        }
+        if let Some(name) = section_name(line, ">>") {
+            return jump_to(&mut self.tokens, &mut self.state, name, position);
+        }
+
+        let references = self.streaming.then_some(&mut self.references);
+        let line = expand_defines(
+            line,
+            line_number,
+            &self.defines,
+            &mut Vec::new(),
+            references,
+        )?;
+        parse_tokens(&mut self.tokens, &line, line_number, &mut self.state)?;
+        self.read_notes = true;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(Sheet, Vec<SheetWarning>), SheetError> {
+        if let Some((_, position)) = self.state.repeat_start {
+            return Err(SheetError::InvalidRepeat(position));
+        }
+        if let Some((_, position)) = self.state.tie {
+            return Err(SheetError::InvalidTie(position));
+        }
+
+        let defines = self
+            .defines
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let header = parse_header(&defines, || Ok(count_notes(&self.tokens)))?;
+        Ok((
+            Sheet {
+                tokens: self.tokens,
+                header,
+            },
+            self.state.warnings,
+        ))
+    }
+}
+
+/// Parses a sheet like [`parse_sheet_with_warnings`], checking its notes as `check` says.
+pub fn parse_sheet_checked(
+    input: &str,
+    remap: &HashMap<char, Key>,
+    check: KeyCheck,
+) -> Result<(Sheet, Vec<SheetWarning>), SheetError> {
+    let input: String = input.nfc().map(ascii_punctuation).collect();
+    // Defines are collected up front so `$name` can refer to one declared further down.
+    let defines = collect_defines(&input)?
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+    let mut parser = LineParser::new(remap, check, defines, false)?;
+    for line in sheet_lines(&input) {
+        parser.line(line)?;
+    }
+    parser.finish()
+}
+
+/// Parses a sheet like [`parse_sheet`] as it is read, a line at a time, so a large sheet is never
+/// held in memory as a whole. Since the defines can't be collected up front, a define must come
+/// before any note that uses it, and `#spaces` and `#repetition` before the notes: one that comes
+/// later fails with [`SheetError::LateDefine`] rather than changing how the notes already read are
+/// played.
+pub fn parse_reader<R: BufRead>(mut reader: R) -> Result<Sheet, SheetError> {
+    let mut parser = LineParser::new(&HashMap::new(), KeyCheck::Lenient, HashMap::new(), true)?;
+    let mut buffer = String::new();
+    loop {
+        buffer.clear();
+        match reader.read_line(&mut buffer) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                return Err(SheetError::NotText);
+            }
+            Err(err) => return Err(SheetError::Read(err)),
+        }
+        let line = buffer.strip_suffix('\n').unwrap_or(&buffer);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let line: String = line.nfc().map(ascii_punctuation).collect();
+        parser.line(&line)?;
+    }
+    parser.finish().map(|(sheet, _)| sheet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distribution() -> PauseDistribution {
+        PauseDistribution {
+            short: 0.2,
+            standard: 0.3,
+            long: 0.5,
+            pause_ratio: 20.0,
+            many_fast_proportion: 0.15,
+            many_fast_gap: 0.0,
+            chord_hold: 1.0,
+        }
+    }
+
+    fn key(character: char) -> Key {
+        Key::Unicode(character)
+    }
+
+    fn tokens(input: &str) -> Vec<Token> {
+        parse_sheet(&format!("#length 1\n{}", input))
+            .unwrap()
+            .tokens
+    }
+
+    #[test]
+    fn repetition_repeats_a_note() {
+        assert_eq!(
+            tokens("#repetition on\na*3 s"),
+            vec![
+                Token::Single(key('a'), None),
+                Token::Single(key('a'), None),
+                Token::Single(key('a'), None),
+                Token::ShortPause,
+                Token::Single(key('s'), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn repetition_repeats_a_chord() {
+        let chord = Token::Many([key('a'), key('b'), key('c')].into_iter().collect(), None);
+        assert_eq!(tokens("#repetition on\n[abc]*3"), vec![chord; 3]);
+    }
+
+    #[test]
+    fn repetition_rejects_a_zero_count() {
+        let error = parse_sheet("#length 1\n#repetition on\nab*0").unwrap_err();
+        assert!(matches!(
+            error,
+            SheetError::InvalidRepetition(Position { line: 3, column: 3 })
+        ));
+    }
+
+    #[test]
+    fn star_is_a_key_without_repetition() {
+        assert_eq!(
+            tokens("8*9( t *9"),
+            [key('8'), key('*'), key('9'), key('(')]
+                .into_iter()
+                .map(|key| Token::Single(key, None))
+                .chain([
+                    Token::ShortPause,
+                    Token::Single(key('t'), None),
+                    Token::ShortPause,
+                    Token::Single(key('*'), None),
+                    Token::Single(key('9'), None),
+                ])
+                .collect::<Vec<_>>()
+        );
+    }
+
+    fn singles(keys: &str) -> Vec<Token> {
+        keys.chars()
+            .map(|character| Token::Single(key(character), None))
+            .collect()
+    }
+
+    #[test]
+    fn jump_plays_a_section_again() {
+        assert_eq!(tokens("@intro\nqw\n@verse\ne\n>>intro"), singles("qweqw"));
+    }
+
+    #[test]
+    fn jump_into_the_open_section() {
+        assert_eq!(tokens("@a\nqw\n>>a"), singles("qwqw"));
+    }
+
+    #[test]
+    fn at_sign_is_a_key_outside_labels() {
+        assert_eq!(tokens("\\@w"), singles("@w"));
+        assert_eq!(tokens("w@"), singles("w@"));
+        assert_eq!(tokens("@w\n\\@w"), singles("@w"));
+    }
+
+    #[test]
+    fn jump_to_an_unknown_section_fails() {
+        assert!(matches!(
+            parse_sheet("#length 1\n@intro\nqw\n>>intor"),
+            Err(SheetError::UnknownSection(name, Position { line: 4, column: 1 })) if name == "intor"
+        ));
+        // A section can only be jumped to after its label.
+        assert!(matches!(
+            parse_sheet("#length 1\n>>verse\n@verse\nqw"),
+            Err(SheetError::UnknownSection(name, Position { line: 2, .. })) if name == "verse"
+        ));
+    }
+
+    #[test]
+    fn bpm_sets_the_note_length() {
+        let sheet = parse_sheet("#bpm 60\na s d").unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        assert!((durations.single.as_secs_f64() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bpm_must_be_positive() {
+        for bpm in ["0", "-60", "fast", "inf"] {
+            let input = format!("#bpm {}\na", bpm);
+            assert!(
+                matches!(parse_sheet(&input), Err(SheetError::InvalidTempo(_))),
+                "#bpm {} was accepted",
+                bpm
+            );
+        }
+    }
+
+    #[test]
+    fn huge_factors_are_rejected() {
+        let huge = "99999999999999999999";
+        let error = |input: &str| parse_sheet(&format!("#length 1\n{}", input)).unwrap_err();
+        assert!(matches!(
+            error(&format!("a:{}", huge)),
+            SheetError::InvalidDuration(_)
+        ));
+        assert!(matches!(
+            error(&format!("a .{}", huge)),
+            SheetError::InvalidRest(_)
+        ));
+        assert!(matches!(
+            error("{rit inf}a{end}"),
+            SheetError::InvalidMarker(_)
+        ));
+        assert!(matches!(
+            error(&format!("{{rit {}}}a{{end}}", huge)),
+            SheetError::InvalidMarker(_)
+        ));
+    }
+
+    #[test]
+    fn schedule_starts_each_token_after_the_last() {
+        let sheet = parse_sheet("#length 10\na s|[df] {rit 2}[g h] j{end} .2\n\nk").unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let factors: Vec<f64> = tempo_factors(&sheet.tokens).collect();
+
+        let mut expected = Duration::ZERO;
+        let mut count = 0;
+        for ((start, token, length), factor) in sheet.schedule(&durations).zip(factors) {
+            assert_eq!(start, expected);
+            assert_eq!(length, token.duration(&durations).mul_f64(factor));
+            expected += length;
+            count += 1;
+        }
+        assert_eq!(count, sheet.tokens.len());
+        assert_eq!(expected, sheet.estimated_duration(&durations));
+    }
+
+    #[test]
+    fn note_count_skips_pauses() {
+        let sheet = parse_sheet("#length 10\na s|[df] [g h]\n\nj~ .2 ~j").unwrap();
+        assert_eq!(sheet.note_count(), 5);
+    }
+
+    #[test]
+    fn durations_fill_the_declared_length() {
+        for input in [
+            "#length 10\na s|[df] [g h]\n\nj~ .2 ~j",
+            "#length 1:30\na | | | s\n\n\nd",
+            "#length 4\nqwerty",
+        ] {
+            let sheet = parse_sheet(input).unwrap();
+            let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+            let estimated = sheet.estimated_duration(&durations).as_secs_f64();
+            assert!(
+                (estimated - sheet.header.length).abs() < 1e-6,
+                "{} plays for {}s",
+                input,
+                estimated
+            );
+        }
+    }
+
+    #[test]
+    fn streamed_sheets_parse_like_strings() {
+        for input in [
+            include_str!("../sheets/bohemian-rhapsody.txt"),
+            include_str!("../sheets/rickroll.txt"),
+            include_str!("../sheets/your-song.txt"),
+            "#length 3\r\n#riff as\r\n$riff d\r\n\r\n\r\n@a\r\nf [gh]\r\n>>a\r\n“j”",
+        ] {
+            assert_eq!(
+                parse_reader(input.as_bytes()).unwrap(),
+                parse_sheet(input).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn streamed_sheets_need_defines_first() {
+        let input = "#length 3\n$riff d\n#riff as";
+        assert_eq!(tokens("$riff d\n#riff as"), tokens("as d"));
+        assert!(matches!(
+            parse_reader(input.as_bytes()),
+            Err(SheetError::LateDefine(name, Position { line: 3, column: 1 })) if name == "riff"
+        ));
+    }
+
+    #[test]
+    fn errors_point_at_the_line_and_column() {
+        let err = parse_sheet("#title Broken\n#length 3\nasd\n\n  f [gh\nj").unwrap_err();
+        assert!(matches!(
+            err,
+            SheetError::UnmatchedBracket(Position { line: 5, column: 5 })
+        ));
+        assert_eq!(err.to_string(), "unmatched '[' at line 5, column 5");
+
+        assert!(matches!(
+            parse_sheet("#length 3\na]").unwrap_err(),
+            SheetError::CloseWithoutOpen(Position { line: 2, column: 2 })
+        ));
+    }
+
+    #[test]
+    fn transpose_counts_the_notes_past_the_edge() {
+        let mut sheet = parse_sheet("#length 3\n1 ! [Bn] m~ ~m").unwrap();
+        assert_eq!(sheet.transpose(2), 3);
+        assert_eq!(sheet.tokens, tokens("2 @ [mm] m~ ~m"));
+
+        let mut dropped = parse_sheet("#length 3\n1 ! [2q]").unwrap();
+        assert_eq!(dropped.transpose_with(-2, ClampPolicy::Drop).unwrap(), 2);
+        assert_eq!(dropped.tokens, tokens(".1 .1 [1(]"));
+
+        let mut refused = dropped.clone();
+        assert!(matches!(
+            refused.transpose_with(-20, ClampPolicy::Error),
+            Err(SheetError::OffKeyboard('1'))
+        ));
+        assert_eq!(refused, dropped);
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(
+            tokens("// intro\na s // the rest\n  // indented\nd"),
+            tokens("a s\nd")
+        );
+        // A comment line neither makes a long pause nor splits one in two.
+        assert_eq!(tokens("a\n// verse\ns"), tokens("a\ns"));
+        assert_eq!(tokens("a\n\n// verse\n\ns"), tokens("a\n\ns"));
+        assert_eq!(
+            tokens("a\n\n// verse\n\ns"),
+            vec![
+                Token::Single(key('a'), None),
+                Token::LongPause,
+                Token::Single(key('s'), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn slashes_in_a_chord_are_notes() {
+        assert_eq!(
+            tokens("[a//s] d"),
+            vec![
+                Token::Many(
+                    [key('a'), key('/'), key('/'), key('s')]
+                        .into_iter()
+                        .collect(),
+                    None
+                ),
+                Token::ShortPause,
+                Token::Single(key('d'), None),
+            ]
+        );
+        // An escaped slash doesn't start a comment, but can come right before one.
+        assert_eq!(tokens("a \\// s"), tokens("a \\/\\/ s"));
+        assert_eq!(tokens("a \\/// s"), tokens("a /"));
+    }
+
+    #[test]
+    fn escaped_brackets_are_notes() {
+        assert_eq!(
+            tokens("\\[a\\] \\\\"),
+            vec![
+                Token::Single(key('['), None),
+                Token::Single(key('a'), None),
+                Token::Single(key(']'), None),
+                Token::ShortPause,
+                Token::Single(key('\\'), None),
+            ]
+        );
+        assert_eq!(
+            tokens("[\\[s\\]\\\\]"),
+            vec![Token::Many(
+                [key('['), key('s'), key(']'), key('\\')]
+                    .into_iter()
+                    .collect(),
+                None
+            )]
+        );
+    }
+
+    #[test]
+    fn defines_expand_where_they_are_used() {
+        assert_eq!(
+            tokens("#chorus [as] d\n#end $chorus f\n$chorus g\n$end"),
+            tokens("[as] d g\n[as] d f")
+        );
+        // `$` is a key too, so a name that isn't defined plays as notes.
+        assert_eq!(tokens("$verse"), tokens("\\$verse"));
+    }
+
+    #[test]
+    fn recursive_defines_are_rejected() {
+        for input in ["#length 1\n#a s $a\n$a", "#length 1\n#a $b\n#b $a\nd\n$b"] {
+            assert!(matches!(
+                parse_sheet(input),
+                Err(SheetError::RecursiveDefine(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn distribution_may_sum_to_one_approximately() {
+        let approximate = PauseDistribution {
+            short: 0.2,
+            standard: 0.7,
+            long: 0.1,
+            ..distribution()
+        };
+        assert_ne!(0.2 + 0.7 + 0.1, 1.0);
+        assert!(calculate_token_durations(1.0, &approximate).is_ok());
+
+        let off = PauseDistribution {
+            long: 0.11,
+            ..approximate
+        };
+        assert!(matches!(
+            calculate_token_durations(1.0, &off),
+            Err(SheetError::InvalidDistribution(_))
+        ));
+    }
+
+    #[test]
+    fn sheets_without_notes_have_no_durations() {
+        for input in ["#length 3\n", "#length 3\n  |\n\n.2 |"] {
+            let sheet = parse_sheet(input).unwrap();
+            assert!(matches!(
+                calculate_token_durations_for(&sheet, &distribution()),
+                Err(SheetError::NoNotes)
+            ));
+        }
+    }
+
+    #[test]
+    fn estimate_adds_up_every_token() {
+        let sheet = parse_sheet("#length 10\na [sd]:2 [f gh]|.2\n\nj~ k ~j").unwrap();
+        let durations = TokenDurations::from_secs(0.1, 0.2, 0.4, 0.5, 0.05, 0.01).unwrap();
+        let millis = Duration::from_millis;
+        let expected = [
+            millis(500),
+            millis(100),
+            millis(1000),
+            millis(100),
+            // Three keys with a gap between each.
+            millis(3 * 50 + 2 * 10),
+            millis(200),
+            // A rest of two notes.
+            millis(1000),
+            millis(400),
+            millis(500),
+            millis(100),
+            millis(500),
+            millis(100),
+        ];
+        assert_eq!(
+            sheet.estimated_duration(&durations),
+            expected.iter().sum::<Duration>()
+        );
+    }
+
+    #[test]
+    fn repeats_expand_the_section() {
+        assert_eq!(tokens("a |:sd:| f").len(), 8);
+        assert_eq!(tokens("a |:sd:| f"), tokens("a sdsd f"));
+        assert_eq!(tokens("|:s d:|x3"), tokens("s ds ds d"));
+        assert_eq!(tokens("|:s\nd:|"), tokens("sdsd"));
+        // Without digits, `x` after a repeat is a note.
+        assert_eq!(tokens("|:s:|x"), tokens("ssx"));
+    }
+
+    #[test]
+    fn repeats_must_be_balanced() {
+        for (input, line, column) in [
+            ("a |:s |:d:| f", 2, 7),
+            ("a s:| f", 2, 4),
+            ("|:a s\nd", 2, 1),
+            ("|:a:|x0", 2, 4),
+        ] {
+            let result = parse_sheet(&format!("#length 1\n{}", input));
+            assert!(
+                matches!(
+                    result,
+                    Err(SheetError::InvalidRepeat(position))
+                        if (position.line, position.column) == (line, column)
+                ),
+                "{}: {:?}",
+                input,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn triplet_takes_the_time_of_two_notes() {
+        let durations = TokenDurations::from_secs(0.1, 0.2, 0.4, 0.5, 0.05, 0.0).unwrap();
+        let length = |input: &str| {
+            parse_sheet(&format!("#length 1\n{}", input))
+                .unwrap()
+                .estimated_duration(&durations)
+        };
+        for (tuplet, notes) in [
+            ("{3 abc}", "ab"),
+            ("{3 a[sd]f}", "ab"),
+            ("{5 qwert}", "qwer"),
+        ] {
+            // Scaling each note rounds it down to the nanosecond.
+            assert!(length(tuplet).abs_diff(length(notes)) < Duration::from_micros(1));
+        }
+    }
+
+    #[test]
+    fn tabs_are_not_notes() {
+        let tabbed = tokens("\ta s\t\n\t[d\tf]");
+        assert_eq!(tabbed, tokens("a s\n[df]"));
+        assert!(!tabbed.iter().any(|token| token.keys().contains(&key('\t'))));
+    }
+
+    #[test]
+    fn spaces_are_single_pauses_unless_scaled() {
+        let a = Token::Single(key('a'), None);
+        let s = Token::Single(key('s'), None);
+        assert_eq!(
+            tokens("a   s"),
+            vec![
+                a.clone(),
+                Token::ShortPause,
+                Token::ShortPause,
+                Token::ShortPause,
+                s.clone(),
+            ]
+        );
+        assert_eq!(
+            tokens("#spaces scaled\na   s a s"),
+            vec![
+                a.clone(),
+                Token::ShortPauses(3),
+                s.clone(),
+                Token::ShortPause,
+                a,
+                Token::ShortPause,
+                s,
+            ]
+        );
+        // Either way a run of spaces takes as long as that many short pauses.
+        let durations = TokenDurations::from_secs(0.1, 0.2, 0.4, 0.5, 0.05, 0.0).unwrap();
+        assert_eq!(
+            Token::ShortPauses(3).duration(&durations),
+            Token::ShortPause.duration(&durations) * 3
+        );
+        assert!(matches!(
+            parse_sheet("#length 1\n#spaces wide\na"),
+            Err(SheetError::InvalidSpaces)
+        ));
+    }
+
+    #[test]
+    fn double_bar_is_a_long_pause() {
+        let a = Token::Single(key('a'), None);
+        assert_eq!(tokens("a|a"), vec![a.clone(), Token::Pause, a.clone()]);
+        assert_eq!(tokens("a||a"), vec![a.clone(), Token::LongPause, a.clone()]);
+        assert_eq!(
+            tokens("a|||a"),
+            vec![a.clone(), Token::LongPause, Token::Pause, a.clone()]
+        );
+        // The second bar starts a repeat instead.
+        assert_eq!(
+            tokens("a||:a:|"),
+            vec![a.clone(), Token::Pause, a.clone(), a.clone()]
+        );
+        assert_eq!(
+            tokens("a\\||a"),
+            vec![a.clone(), Token::Single(key('|'), None), Token::Pause, a]
+        );
+    }
+
+    #[test]
+    fn decomposed_accents_are_composed_first() {
+        let (sheet, warnings) =
+            parse_sheet_with_warnings("#length 1\na e\u{301} s", &HashMap::new()).unwrap();
+        assert_eq!(sheet.tokens, tokens("a  s"));
+        assert!(matches!(
+            warnings[..],
+            [SheetWarning::UnknownCharacter(
+                'é',
+                Position { line: 2, column: 3 }
+            )]
+        ));
+
+        // Composed, it is one character that a remapping can give a key.
+        let remap = HashMap::from([('é', key('e'))]);
+        let (sheet, warnings) = parse_sheet_with_warnings("#length 1\ne\u{301}", &remap).unwrap();
+        assert_eq!(sheet.tokens, tokens("e"));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn typographic_punctuation_reads_as_ascii() {
+        assert_eq!(
+            tokens("\u{2018}a\u{2019} \u{201C}s\u{201D}\u{2014}"),
+            tokens("'a' \"s\"-")
+        );
+    }
+
+    #[test]
+    fn length_formats() {
+        for (length, secs) in [
+            ("220", 220.0),
+            ("3:40", 220.0),
+            ("1:02:03", 3723.0),
+            ("3m40s", 220.0),
+            ("1h2m3s", 3723.0),
+            ("90s", 90.0),
+            ("2m", 120.0),
+            ("1:30.5", 90.5),
+        ] {
+            assert_eq!(parse_length(length).unwrap(), secs, "{}", length);
+        }
+        for length in ["", "3:40:10:5", "three minutes", "3x40"] {
+            assert!(
+                matches!(
+                    parse_length(length),
+                    Err(SheetError::InvalidLengthFormat(_))
+                ),
+                "{}",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn length_must_be_finite_and_not_negative() {
+        for length in [
+            "-5", "-0.5", "inf", "NaN", "1e400", "-1:30", "1:-30", "-2m", "1e308h",
+        ] {
+            assert!(
+                matches!(
+                    parse_length(length),
+                    Err(SheetError::InvalidLengthFormat(_))
+                ),
+                "{}",
+                length
+            );
+        }
+        assert_eq!(parse_length("0").unwrap(), 0.0);
+        assert!(parse_sheet("#length -5\na").is_err());
+    }
+
+    #[test]
+    fn every_define_is_in_the_metadata() {
+        let sheet =
+            parse_sheet("#title Take Five\n#genre jazz\n#year 1959\n#length 5:24\na").unwrap();
+        let header = &sheet.header;
+        assert_eq!(header.title.as_deref(), Some("Take Five"));
+        assert_eq!(header.metadata["genre"], "jazz");
+        assert_eq!(header.metadata["year"], "1959");
+        assert_eq!(header.metadata["length"], "5:24");
+        assert_eq!(header.metadata.len(), 4);
+    }
+
+    #[test]
+    fn crlf_sheets_read_like_unix_ones() {
+        let unix = "#title Foo\n#writer Bar\n#length 1\na s\n\nd\n";
+        let windows = unix.replace('\n', "\r\n");
+        let sheet = parse_sheet(&windows).unwrap();
+        assert_eq!(sheet.header.title.as_deref(), Some("Foo"));
+        assert_eq!(sheet, parse_sheet(unix).unwrap());
+        // Including a last line without a line break.
+        assert_eq!(
+            parse_sheet("#length 1\r\n#title Foo\r")
+                .unwrap()
+                .header
+                .title
+                .as_deref(),
+            Some("Foo")
+        );
+    }
+
+    #[test]
+    fn weights_normalize_to_fractions() {
+        let weights = PauseDistribution {
+            short: 2.0,
+            standard: 3.0,
+            long: 5.0,
+            ..distribution()
+        }
+        .normalized();
+        assert_eq!(
+            (weights.short, weights.standard, weights.long),
+            (0.2, 0.3, 0.5)
+        );
+        assert!(calculate_token_durations(1.0, &weights).is_ok());
+
+        let zero = PauseDistribution {
+            short: 0.0,
+            standard: 0.0,
+            long: 0.0,
+            ..distribution()
+        }
+        .normalized();
+        assert_eq!(zero.short + zero.standard + zero.long, 0.0);
+        assert!(matches!(
+            calculate_token_durations(1.0, &zero),
+            Err(SheetError::InvalidDistribution(_))
+        ));
+    }
+
+    #[test]
+    fn notes_share_what_the_pauses_leave() {
+        let sheet = parse_sheet("#length 10\na | | s  d\n\nf").unwrap();
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let pauses = durations.pause * 2 + durations.short_pause * 5 + durations.long_pause;
+        // Four notes out of twelve tokens, so dividing by the token count would be far too short.
+        let per_note = (Duration::from_secs(10) - pauses) / 4;
+        assert!(durations.single.abs_diff(per_note) < Duration::from_micros(1));
+        assert_eq!(sheet.tokens.len(), 12);
+        assert!(durations.single > Duration::from_secs(10) / 12);
+    }
+
+    #[test]
+    fn clamp_policies_at_both_ends() {
+        let sheet = parse_sheet("#length 3\n1 [2m] n~ q ~n").unwrap();
+        let transposed = |steps, policy| {
+            let mut sheet = sheet.clone();
+            let adjusted = sheet.transpose_with(steps, policy);
+            (adjusted.ok(), sheet.tokens)
+        };
+
+        assert_eq!(
+            transposed(2, ClampPolicy::Nearest),
+            (Some(3), tokens("2 [3m] m~ w ~m"))
+        );
+        assert_eq!(
+            transposed(-2, ClampPolicy::Nearest),
+            (Some(1), tokens("1 [1B] b~ ( ~b"))
+        );
+        // A dropped note leaves a rest, so the notes after it keep their place, and a chord keeps
+        // its other notes. The release of a dropped sustain goes with it.
+        assert_eq!(
+            transposed(2, ClampPolicy::Drop),
+            (Some(3), tokens("2 [3] .1 w "))
+        );
+        assert_eq!(
+            transposed(-2, ClampPolicy::Drop),
+            (Some(1), tokens(".1 [1B] b~ ( ~b"))
+        );
+        assert_eq!(
+            transposed(2, ClampPolicy::Error),
+            (None, sheet.tokens.clone())
+        );
+        assert_eq!(
+            transposed(-2, ClampPolicy::Error),
+            (None, sheet.tokens.clone())
+        );
+        assert_eq!(
+            transposed(1, ClampPolicy::Error),
+            (None, sheet.tokens.clone())
+        );
+        assert_eq!(
+            transposed(-1, ClampPolicy::Error),
+            (None, sheet.tokens.clone())
+        );
+    }
+
+    #[test]
+    fn parses_each_kind_of_token() {
+        let cases = [
+            ("a", vec![Token::Single(key('a'), None)]),
+            ("a:2", vec![Token::Single(key('a'), Some(2.0))]),
+            (
+                "[as]",
+                vec![Token::Many(
+                    [key('a'), key('s')].into_iter().collect(),
+                    None,
+                )],
+            ),
+            (
+                "[a s]",
+                vec![Token::ManyFast([key('a'), key('s')].into_iter().collect())],
+            ),
+            (
+                "a s",
+                vec![
+                    Token::Single(key('a'), None),
+                    Token::ShortPause,
+                    Token::Single(key('s'), None),
+                ],
+            ),
+            ("|", vec![Token::Pause]),
+            (".2", vec![Token::Rest(2.0)]),
+            (
+                "a~ ~a",
+                vec![
+                    Token::Hold(key('a')),
+                    Token::ShortPause,
+                    Token::ReleaseHold(key('a')),
+                ],
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(tokens(input), expected, "{input:?}");
+        }
+
+        let sheet = parse_sheet("#title Sheet\n#length 1\na").unwrap();
+        assert_eq!(sheet, sheet.clone());
+        assert_ne!(sheet, parse_sheet("#title Other\n#length 1\na").unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip_keeps_the_sheet() {
+        let sheet = parse_sheet("#title Round trip\n#length 1\na [sd]:2 | [f g] .2 h~ ~h").unwrap();
+        let json = serde_json::to_string(&sheet).unwrap();
+        assert_eq!(serde_json::from_str::<Sheet>(&json).unwrap(), sheet);
+
+        let durations = calculate_token_durations_for(&sheet, &distribution()).unwrap();
+        let json = serde_json::to_string(&durations).unwrap();
+        let parsed: TokenDurations = serde_json::from_str(&json).unwrap();
+        assert_eq!(format!("{parsed:?}"), format!("{durations:?}"));
+    }
 }
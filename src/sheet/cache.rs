@@ -0,0 +1,163 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    env, fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{Sheet, json::JsonSheet};
+
+/// Entries are keyed by the canonical path, so a file reached by different paths has one entry.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// One cached sheet, with the file it was parsed from and that file's modification time then.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    path: PathBuf,
+    modified: SystemTime,
+    sheet: JsonSheet,
+}
+
+/// Parsed sheets saved on disk, so a file that hasn't changed doesn't have to be parsed again.
+/// Entries are keyed by path and remember the file's modification time, so an entry for a file
+/// that has been touched since is a miss.
+///
+/// The cache doesn't know how a sheet was parsed: use a separate directory for each set of
+/// parsing options, such as a key remapping.
+#[derive(Clone)]
+pub struct SheetCache {
+    dir: PathBuf,
+}
+
+impl SheetCache {
+    pub fn new(dir: impl Into<PathBuf>) -> SheetCache {
+        SheetCache { dir: dir.into() }
+    }
+
+    /// A cache in the user's cache directory: `$XDG_CACHE_HOME`, `~/.cache` or `%LOCALAPPDATA%`.
+    pub fn user() -> Option<SheetCache> {
+        let base = env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+        Some(SheetCache::new(base.join("virtualpiano-rs")))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, path: &Path) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.json", hasher.finish()))
+    }
+
+    /// The sheet cached for `path`, if it was cached when the file was last modified at
+    /// `modified`. An unreadable or outdated entry is treated as missing, with a warning if it
+    /// is there but can't be read back.
+    pub fn get(&self, path: &Path, modified: SystemTime) -> Option<Sheet> {
+        let path = canonical(path);
+        let entry_path = self.entry_path(&path);
+        let contents = match fs::read(&entry_path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                warn!("Could not read {}: {}", entry_path.display(), err);
+                return None;
+            }
+        };
+        let entry: CacheEntry = match serde_json::from_slice(&contents) {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("Could not read {}: {}", entry_path.display(), err);
+                return None;
+            }
+        };
+        if entry.path != path || entry.modified != modified {
+            return None;
+        }
+        match entry.sheet.into_sheet() {
+            Ok(sheet) => Some(sheet),
+            Err(err) => {
+                warn!(
+                    "Could not read the cached sheet for {}: {}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Saves `sheet` as parsed from `path` while it was last modified at `modified`, replacing
+    /// any older entry.
+    pub fn insert(&self, path: &Path, modified: SystemTime, sheet: &Sheet) -> io::Result<()> {
+        let path = canonical(path);
+        let entry = CacheEntry {
+            path: path.clone(),
+            modified,
+            sheet: JsonSheet::from_sheet(sheet),
+        };
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.entry_path(&path), serde_json::to_vec(&entry)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::sheet::parse_sheet;
+
+    /// A cache and a sheet file in a fresh directory under the system's temporary one.
+    fn setup(name: &str) -> (PathBuf, SheetCache, PathBuf) {
+        let dir = env::temp_dir().join(format!("virtualpiano-rs-{}-{}", name, std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("song.txt");
+        fs::write(&file, "#length 1\na s [df]").unwrap();
+        (dir.clone(), SheetCache::new(dir.join("cache")), file)
+    }
+
+    #[test]
+    fn hit_until_the_file_is_touched() {
+        let (dir, cache, file) = setup("touched");
+        let sheet = parse_sheet(&fs::read_to_string(&file).unwrap()).unwrap();
+        let modified = fs::metadata(&file).unwrap().modified().unwrap();
+
+        assert_eq!(cache.get(&file, modified), None);
+        cache.insert(&file, modified, &sheet).unwrap();
+        assert_eq!(cache.get(&file, modified), Some(sheet));
+
+        let touched = modified + Duration::from_secs(1);
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(touched)
+            .unwrap();
+        let modified = fs::metadata(&file).unwrap().modified().unwrap();
+        assert_eq!(cache.get(&file, modified), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_entry_is_a_miss() {
+        let (dir, cache, file) = setup("corrupt");
+        let modified = fs::metadata(&file).unwrap().modified().unwrap();
+        fs::create_dir_all(cache.dir()).unwrap();
+        fs::write(cache.entry_path(&canonical(&file)), "not json").unwrap();
+        assert_eq!(cache.get(&file, modified), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
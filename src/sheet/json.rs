@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+
+use enigo::Key;
+use serde::{Deserialize, Serialize};
+
+use super::{Chord, Header, MAX_FACTOR, Sheet, SheetError, Token};
+
+/// A sheet as JSON: the header fields next to a list of tokens.
+#[derive(Serialize, Deserialize)]
+pub(super) struct JsonSheet {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    writer: Option<String>,
+    length: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bpm: Option<f64>,
+    /// Sorted, so the same sheet is always written the same way.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+    tokens: Vec<JsonToken>,
+}
+
+/// One [`Token`], tagged with its variant name in snake case, e.g.
+/// `{"type": "single", "key": "t", "length": 2}`.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonToken {
+    ShortPause,
+    ShortPauses {
+        count: usize,
+    },
+    Pause,
+    LongPause,
+    Rest {
+        notes: f64,
+    },
+    Single {
+        key: char,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        length: Option<f64>,
+    },
+    Tied {
+        key: char,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        length: Option<f64>,
+    },
+    Many {
+        keys: Vec<char>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        length: Option<f64>,
+    },
+    ManyFast {
+        keys: Vec<char>,
+    },
+    TempoStart {
+        factor: f64,
+    },
+    TempoEnd,
+    Hold {
+        key: char,
+    },
+    ReleaseHold {
+        key: char,
+    },
+}
+
+fn invalid(reason: &str) -> SheetError {
+    SheetError::InvalidJson(reason.to_string())
+}
+
+/// Checks that a factor is a usable multiple of a duration, as the text parser does.
+fn positive(value: f64, name: &str) -> Result<f64, SheetError> {
+    match value > 0.0 && value.is_finite() {
+        true => Ok(value),
+        false => Err(invalid(&format!("{} must be greater than zero", name))),
+    }
+}
+
+/// Checks a length factor, rest or tempo target like [`positive`], also keeping it to
+/// [`MAX_FACTOR`].
+fn bounded(value: f64, name: &str) -> Result<f64, SheetError> {
+    match positive(value, name)? <= MAX_FACTOR {
+        true => Ok(value),
+        false => Err(invalid(&format!("{} must be at most {}", name, MAX_FACTOR))),
+    }
+}
+
+fn factor(length: Option<f64>) -> Result<Option<f64>, SheetError> {
+    length.map(|length| bounded(length, "length")).transpose()
+}
+
+/// The keys of a chord. Like `[]` in the text format, an empty chord is a rest as long as a note.
+fn chord(keys: Vec<char>) -> Chord {
+    keys.into_iter().map(Key::Unicode).collect()
+}
+
+impl JsonToken {
+    fn into_token(self) -> Result<Token, SheetError> {
+        Ok(match self {
+            JsonToken::ShortPause => Token::ShortPause,
+            JsonToken::ShortPauses { count: 0 } => return Err(invalid("count must not be zero")),
+            JsonToken::ShortPauses { count } => Token::ShortPauses(count),
+            JsonToken::Pause => Token::Pause,
+            JsonToken::LongPause => Token::LongPause,
+            JsonToken::Rest { notes } => Token::Rest(bounded(notes, "notes")?),
+            JsonToken::Single { key, length } => Token::Single(Key::Unicode(key), factor(length)?),
+            JsonToken::Tied { key, length } => Token::Tied(Key::Unicode(key), factor(length)?),
+            JsonToken::Many { keys, length } => Token::Many(chord(keys), factor(length)?),
+            JsonToken::ManyFast { keys } => Token::ManyFast(chord(keys)),
+            JsonToken::TempoStart { factor } => Token::TempoStart(bounded(factor, "factor")?),
+            JsonToken::TempoEnd => Token::TempoEnd,
+            JsonToken::Hold { key } => Token::Hold(Key::Unicode(key)),
+            JsonToken::ReleaseHold { key } => Token::ReleaseHold(Key::Unicode(key)),
+        })
+    }
+
+    /// The JSON form of `token`, or `None` for a note on a key that isn't a character.
+    fn from_token(token: &Token) -> Option<JsonToken> {
+        let character = |key: &Key| match *key {
+            Key::Unicode(character) => Some(character),
+            _ => None,
+        };
+        let characters = |keys: &[Key]| keys.iter().filter_map(character).collect::<Vec<_>>();
+
+        Some(match token {
+            Token::ShortPause => JsonToken::ShortPause,
+            Token::ShortPauses(count) => JsonToken::ShortPauses { count: *count },
+            Token::Pause => JsonToken::Pause,
+            Token::LongPause => JsonToken::LongPause,
+            Token::Rest(notes) => JsonToken::Rest { notes: *notes },
+            Token::Single(key, length) => JsonToken::Single {
+                key: character(key)?,
+                length: *length,
+            },
+            Token::Tied(key, length) => JsonToken::Tied {
+                key: character(key)?,
+                length: *length,
+            },
+            Token::Many(keys, length) => JsonToken::Many {
+                keys: characters(keys),
+                length: *length,
+            },
+            Token::ManyFast(keys) => JsonToken::ManyFast {
+                keys: characters(keys),
+            },
+            Token::TempoStart(factor) => JsonToken::TempoStart { factor: *factor },
+            Token::TempoEnd => JsonToken::TempoEnd,
+            Token::Hold(key) => JsonToken::Hold {
+                key: character(key)?,
+            },
+            Token::ReleaseHold(key) => JsonToken::ReleaseHold {
+                key: character(key)?,
+            },
+        })
+    }
+}
+
+impl JsonSheet {
+    pub(super) fn from_sheet(sheet: &Sheet) -> JsonSheet {
+        JsonSheet {
+            title: sheet.header.title.clone(),
+            writer: sheet.header.writer.clone(),
+            length: sheet.header.length,
+            bpm: sheet.header.bpm,
+            metadata: sheet.header.metadata.clone().into_iter().collect(),
+            tokens: sheet
+                .tokens
+                .iter()
+                .filter_map(JsonToken::from_token)
+                .collect(),
+        }
+    }
+
+    pub(super) fn into_sheet(self) -> Result<Sheet, SheetError> {
+        if !self.length.is_finite() || self.length < 0.0 {
+            return Err(invalid("length must be a number of seconds"));
+        }
+        let bpm = self.bpm.map(|bpm| positive(bpm, "bpm")).transpose()?;
+        let tokens = self
+            .tokens
+            .into_iter()
+            .map(JsonToken::into_token)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Sheet {
+            header: Header {
+                title: self.title,
+                writer: self.writer,
+                length: self.length,
+                bpm,
+                metadata: self.metadata.into_iter().collect(),
+            },
+            tokens,
+        })
+    }
+}
+
+/// Reads a sheet from its JSON form, as written by [`to_json`]. Sections and repeats have no JSON
+/// form, since the tokens are already laid out in playing order.
+pub fn from_json(input: &str) -> Result<Sheet, SheetError> {
+    let json: JsonSheet =
+        serde_json::from_str(input).map_err(|err| SheetError::InvalidJson(err.to_string()))?;
+    json.into_sheet()
+}
+
+/// Writes `sheet` as pretty-printed JSON that [`from_json`] reads back to the same sheet.
+pub fn to_json(sheet: &Sheet) -> String {
+    serde_json::to_string_pretty(&JsonSheet::from_sheet(sheet)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::parse_sheet;
+
+    #[test]
+    fn json_round_trip() {
+        let json = r#"{
+  "title": "Scale",
+  "length": 3.0,
+  "metadata": {
+    "length": "3",
+    "title": "Scale"
+  },
+  "tokens": [
+    {
+      "type": "single",
+      "key": "a",
+      "length": 2.0
+    },
+    {
+      "type": "short_pause"
+    },
+    {
+      "type": "many",
+      "keys": [
+        "s",
+        "d"
+      ]
+    },
+    {
+      "type": "many",
+      "keys": []
+    },
+    {
+      "type": "rest",
+      "notes": 0.5
+    },
+    {
+      "type": "hold",
+      "key": "f"
+    },
+    {
+      "type": "release_hold",
+      "key": "f"
+    }
+  ]
+}"#;
+        assert_eq!(to_json(&from_json(json).unwrap()), json);
+    }
+
+    #[test]
+    fn bundled_sheets_round_trip() {
+        for sheet in [
+            include_str!("../../sheets/bohemian-rhapsody.txt"),
+            include_str!("../../sheets/rickroll.txt"),
+            include_str!("../../sheets/your-song.txt"),
+        ] {
+            let sheet = parse_sheet(sheet).unwrap();
+            assert_eq!(from_json(&to_json(&sheet)).unwrap(), sheet);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use super::{
+    Header, Sheet, SheetError, from_json, from_midi, from_virtualpiano, parse_header_only,
+    parse_sheet,
+};
+
+/// Reads one sheet file format. Register an implementation with [`SheetParsers::register`] to
+/// load another format alongside the built-in ones.
+pub trait SheetParser: Send + Sync {
+    fn parse(&self, input: &str) -> Result<Sheet, SheetError>;
+
+    /// Parses the raw contents of a file. Text formats can rely on this decoding the file as
+    /// UTF-8, and binary formats override it.
+    fn parse_bytes(&self, bytes: &[u8]) -> Result<Sheet, SheetError> {
+        let text = std::str::from_utf8(bytes).map_err(|_| SheetError::NotText)?;
+        self.parse(text)
+    }
+
+    /// Reads just the header from the raw contents of a file. Formats that can find it without
+    /// parsing the notes override this.
+    fn parse_header(&self, bytes: &[u8]) -> Result<Header, SheetError> {
+        self.parse_bytes(bytes).map(|sheet| sheet.header)
+    }
+}
+
+/// The plain-text format described at the top of [`crate::sheet`].
+pub struct TextParser;
+
+impl SheetParser for TextParser {
+    fn parse(&self, input: &str) -> Result<Sheet, SheetError> {
+        parse_sheet(input)
+    }
+
+    fn parse_header(&self, bytes: &[u8]) -> Result<Header, SheetError> {
+        parse_header_only(std::str::from_utf8(bytes).map_err(|_| SheetError::NotText)?)
+    }
+}
+
+/// Sheets written by [`super::to_json`].
+pub struct JsonParser;
+
+impl SheetParser for JsonParser {
+    fn parse(&self, input: &str) -> Result<Sheet, SheetError> {
+        from_json(input)
+    }
+}
+
+/// Sheets copied from virtualpiano.net, read with [`from_virtualpiano`].
+pub struct VirtualPianoParser;
+
+impl SheetParser for VirtualPianoParser {
+    fn parse(&self, input: &str) -> Result<Sheet, SheetError> {
+        from_virtualpiano(input)
+    }
+}
+
+/// Standard MIDI files, read with [`from_midi`].
+pub struct MidiParser;
+
+impl SheetParser for MidiParser {
+    fn parse(&self, input: &str) -> Result<Sheet, SheetError> {
+        self.parse_bytes(input.as_bytes())
+    }
+
+    fn parse_bytes(&self, bytes: &[u8]) -> Result<Sheet, SheetError> {
+        from_midi(bytes)
+    }
+}
+
+/// Parsers keyed by the file extension they read, without the dot. The default registry reads
+/// `.txt`, `.json`, `.vp` and `.mid` files.
+#[derive(Clone)]
+pub struct SheetParsers {
+    parsers: HashMap<String, Arc<dyn SheetParser>>,
+}
+
+impl SheetParsers {
+    /// A registry without any parsers.
+    pub fn empty() -> SheetParsers {
+        SheetParsers {
+            parsers: HashMap::new(),
+        }
+    }
+
+    /// Reads files ending in `.extension` with `parser`, replacing any parser already registered
+    /// for it.
+    pub fn register(&mut self, extension: &str, parser: impl SheetParser + 'static) {
+        self.parsers
+            .insert(extension.to_ascii_lowercase(), Arc::new(parser));
+    }
+
+    /// The parser for the extension of `path`, ignoring case.
+    pub fn parser_for(&self, path: &Path) -> Option<&dyn SheetParser> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        self.parsers.get(&extension).map(|parser| parser.as_ref())
+    }
+
+    pub fn supports(&self, path: &Path) -> bool {
+        self.parser_for(path).is_some()
+    }
+
+    /// The parser for `path` and the contents of the file.
+    fn read(&self, path: &Path) -> Result<(&dyn SheetParser, Vec<u8>), SheetError> {
+        let Some(parser) = self.parser_for(path) else {
+            let extension = path.extension().unwrap_or_default();
+            return Err(SheetError::UnknownFormat(
+                extension.to_string_lossy().into_owned(),
+            ));
+        };
+
+        Ok((parser, fs::read(path).map_err(SheetError::Read)?))
+    }
+
+    /// Reads the sheet at `path` with the parser registered for its extension.
+    pub fn load(&self, path: &Path) -> Result<Sheet, SheetError> {
+        let (parser, bytes) = self.read(path)?;
+        parser.parse_bytes(&bytes)
+    }
+
+    /// Reads only the header of the sheet at `path`, see [`SheetParser::parse_header`].
+    pub fn load_header(&self, path: &Path) -> Result<Header, SheetError> {
+        let (parser, bytes) = self.read(path)?;
+        parser.parse_header(&bytes)
+    }
+}
+
+impl Default for SheetParsers {
+    fn default() -> Self {
+        let mut parsers = SheetParsers::empty();
+        parsers.register("txt", TextParser);
+        parsers.register("json", JsonParser);
+        parsers.register("vp", VirtualPianoParser);
+        parsers.register("mid", MidiParser);
+        parsers
+    }
+}
+
+/// Reads the sheet at `path` with the built-in parser for its extension: `.txt` for the text
+/// format, `.json` for [`from_json`], `.vp` for [`from_virtualpiano`] and `.mid` for
+/// [`from_midi`].
+pub fn load_any(path: &Path) -> Result<Sheet, SheetError> {
+    SheetParsers::default().load(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// Bare notes, with a length of a second per line.
+    struct NotesParser;
+
+    impl SheetParser for NotesParser {
+        fn parse(&self, input: &str) -> Result<Sheet, SheetError> {
+            parse_sheet(&format!("#length {}\n{}", input.lines().count(), input))
+        }
+    }
+
+    #[test]
+    fn custom_parsers_load_through_the_registry() {
+        let dir = env::temp_dir().join(format!("virtualpiano-rs-parsers-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("riff.NOTES");
+        fs::write(&file, "a s\nd").unwrap();
+
+        let mut parsers = SheetParsers::default();
+        assert!(matches!(
+            parsers.load(&file),
+            Err(SheetError::UnknownFormat(extension)) if extension == "NOTES"
+        ));
+
+        parsers.register("notes", NotesParser);
+        assert!(parsers.supports(&file));
+        let sheet = parsers.load(&file).unwrap();
+        assert_eq!(sheet, parse_sheet("#length 2\na s\nd").unwrap());
+        assert_eq!(parsers.load_header(&file).unwrap().length, 2.0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use std::{collections::HashMap, time::Duration};
+
+use enigo::Key;
+use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use super::{Header, Sheet, SheetError, Token, TokenDurations, tokens_from_onsets};
+use crate::layout;
+
+/// General MIDI reserves this channel for percussion, which has no pitch to map.
+const DRUM_CHANNEL: u8 = 9;
+const DEFAULT_TEMPO: f64 = 500_000.0;
+/// Exported files use one-millisecond ticks: a 1000-tick beat lasting one second.
+const EXPORT_TICKS_PER_BEAT: u16 = 1000;
+const EXPORT_TEMPO: u32 = 1_000_000;
+const EXPORT_VELOCITY: u8 = 100;
+
+struct NoteOn {
+    time: f64,
+    note: u8,
+}
+
+/// Converts absolute ticks to seconds, honouring every tempo change seen so far.
+struct TempoMap {
+    ticks_per_beat: Option<f64>,
+    ticks_per_second: f64,
+    changes: Vec<(u64, f64)>,
+}
+
+impl TempoMap {
+    fn seconds(&self, tick: u64) -> f64 {
+        let Some(ticks_per_beat) = self.ticks_per_beat else {
+            return tick as f64 / self.ticks_per_second;
+        };
+
+        let mut seconds = 0.0;
+        let mut last_tick = 0;
+        let mut tempo = DEFAULT_TEMPO;
+        for &(change_tick, change_tempo) in &self.changes {
+            if change_tick >= tick {
+                break;
+            }
+            seconds += (change_tick - last_tick) as f64 * tempo / ticks_per_beat / 1_000_000.0;
+            last_tick = change_tick;
+            tempo = change_tempo;
+        }
+        seconds + (tick - last_tick) as f64 * tempo / ticks_per_beat / 1_000_000.0
+    }
+}
+
+pub fn from_midi(bytes: &[u8]) -> Result<Sheet, SheetError> {
+    let smf = Smf::parse(bytes).map_err(|err| SheetError::InvalidMidi(err.to_string()))?;
+
+    let mut tempo_map = match smf.header.timing {
+        Timing::Metrical(ticks) => TempoMap {
+            ticks_per_beat: Some(ticks.as_int() as f64),
+            ticks_per_second: 0.0,
+            changes: Vec::new(),
+        },
+        Timing::Timecode(fps, subframes) => TempoMap {
+            ticks_per_beat: None,
+            ticks_per_second: fps.as_f32() as f64 * subframes as f64,
+            changes: Vec::new(),
+        },
+    };
+
+    let mut title = None;
+    let mut note_ticks = Vec::new();
+    let mut end_tick = 0;
+    for track in &smf.tracks {
+        let mut tick = 0;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                    tempo_map.changes.push((tick, tempo.as_int() as f64));
+                }
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) if title.is_none() => {
+                    let name = String::from_utf8_lossy(name).trim().to_string();
+                    if !name.is_empty() {
+                        title = Some(name);
+                    }
+                }
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, vel },
+                } if vel > 0 && channel != DRUM_CHANNEL => {
+                    note_ticks.push((tick, key.as_int()));
+                }
+                _ => {}
+            }
+        }
+        end_tick = end_tick.max(tick);
+    }
+    tempo_map.changes.sort_by_key(|&(tick, _)| tick);
+
+    let mut notes: Vec<NoteOn> = note_ticks
+        .into_iter()
+        .map(|(tick, note)| NoteOn {
+            time: tempo_map.seconds(tick),
+            note,
+        })
+        .collect();
+    notes.sort_by(|a, b| a.time.total_cmp(&b.time).then(a.note.cmp(&b.note)));
+
+    let onsets: Vec<(f64, Key)> = notes
+        .into_iter()
+        .filter_map(|note| Some((note.time, Key::Unicode(layout::note_to_char(note.note)?))))
+        .collect();
+    let tokens = tokens_from_onsets(&onsets);
+
+    Ok(Sheet {
+        header: Header {
+            title,
+            writer: None,
+            length: tempo_map.seconds(end_tick),
+            bpm: None,
+            metadata: HashMap::new(),
+        },
+        tokens,
+    })
+}
+
+fn export_note(key: &Key) -> Option<u8> {
+    match key {
+        Key::Unicode(character) => layout::char_to_note(*character),
+        _ => None,
+    }
+}
+
+pub fn to_midi(sheet: &Sheet, durations: &TokenDurations) -> Vec<u8> {
+    // (time, note, is note-on)
+    let mut events: Vec<(Duration, u8, bool)> = Vec::new();
+    let mut time = Duration::ZERO;
+    let mut held = Vec::new();
+    let mut tied = Vec::new();
+    for token in &sheet.tokens {
+        match token {
+            // Tempo spans only affect live playback.
+            Token::TempoStart(_) | Token::TempoEnd => {}
+            Token::ShortPause
+            | Token::ShortPauses(_)
+            | Token::Pause
+            | Token::LongPause
+            | Token::Rest(_) => time += token.duration(durations),
+            Token::Single(key, length) => {
+                let hold = durations.single.mul_f64(length.unwrap_or(1.0));
+                if let Some(note) = export_note(key) {
+                    match tied.iter().position(|&tied| tied == note) {
+                        Some(index) => _ = tied.remove(index),
+                        None => events.push((time, note, true)),
+                    }
+                    events.push((time + hold, note, false));
+                }
+                time += hold;
+            }
+            Token::Tied(key, length) => {
+                if let Some(note) = export_note(key)
+                    && !tied.contains(&note)
+                {
+                    events.push((time, note, true));
+                    tied.push(note);
+                }
+                time += durations.single.mul_f64(length.unwrap_or(1.0));
+            }
+            Token::Many(keys, length) => {
+                let step = durations.single.mul_f64(length.unwrap_or(1.0));
+                let hold = step.mul_f64(durations.chord_hold);
+                for note in keys.iter().filter_map(export_note) {
+                    events.push((time, note, true));
+                    events.push((time + hold, note, false));
+                }
+                time += step;
+            }
+            Token::ManyFast(keys) => {
+                for (index, key) in keys.iter().enumerate() {
+                    if index > 0 {
+                        time += durations.many_fast_gap;
+                    }
+                    if let Some(note) = export_note(key) {
+                        events.push((time, note, true));
+                        events.push((time + durations.many_fast, note, false));
+                    }
+                    time += durations.many_fast;
+                }
+            }
+            Token::Hold(key) => {
+                if let Some(note) = export_note(key) {
+                    match tied.iter().position(|&tied| tied == note) {
+                        Some(index) => _ = tied.remove(index),
+                        None => events.push((time, note, true)),
+                    }
+                    held.push(note);
+                }
+                time += durations.single;
+            }
+            Token::ReleaseHold(key) => {
+                if let Some(note) = export_note(key)
+                    && let Some(index) = held.iter().position(|&held| held == note)
+                {
+                    held.remove(index);
+                    events.push((time, note, false));
+                }
+            }
+        }
+    }
+    // Sustains that are never released end with the song.
+    events.extend(held.into_iter().chain(tied).map(|note| (time, note, false)));
+    // Releases sort before presses at the same instant so repeated notes retrigger.
+    events.sort_by_key(|&(time, _, on)| (time, on));
+
+    let mut track = Vec::new();
+    if let Some(title) = &sheet.header.title {
+        track.push(TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(title.as_bytes())),
+        });
+    }
+    track.push(TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Meta(MetaMessage::Tempo(EXPORT_TEMPO.into())),
+    });
+
+    let mut last_tick = 0;
+    for (event_time, note, on) in events {
+        let tick = (event_time.as_secs_f64() * EXPORT_TICKS_PER_BEAT as f64).round() as u32;
+        let message = if on {
+            MidiMessage::NoteOn {
+                key: note.into(),
+                vel: EXPORT_VELOCITY.into(),
+            }
+        } else {
+            MidiMessage::NoteOff {
+                key: note.into(),
+                vel: 0.into(),
+            }
+        };
+        track.push(TrackEvent {
+            delta: (tick - last_tick).into(),
+            kind: TrackEventKind::Midi {
+                channel: 0.into(),
+                message,
+            },
+        });
+        last_tick = tick;
+    }
+    let end_tick = (time.as_secs_f64() * EXPORT_TICKS_PER_BEAT as f64).round() as u32;
+    track.push(TrackEvent {
+        delta: end_tick.saturating_sub(last_tick).into(),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+
+    let mut smf = Smf::new(midly::Header::new(
+        Format::SingleTrack,
+        Timing::Metrical(EXPORT_TICKS_PER_BEAT.into()),
+    ));
+    smf.tracks.push(track);
+
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes).unwrap();
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PauseDistribution, calculate_token_durations_for, parse_sheet};
+
+    fn durations(sheet: &Sheet) -> TokenDurations {
+        let distribution = PauseDistribution {
+            short: 0.2,
+            standard: 0.3,
+            long: 0.5,
+            pause_ratio: 20.0,
+            many_fast_proportion: 0.15,
+            many_fast_gap: 0.0,
+            chord_hold: 1.0,
+        };
+        calculate_token_durations_for(sheet, &distribution).unwrap()
+    }
+
+    /// The notes a file starts, in the order it starts them.
+    fn note_ons(bytes: &[u8]) -> Vec<u8> {
+        let smf = Smf::parse(bytes).unwrap();
+        smf.tracks[0]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { key, vel },
+                    ..
+                } if vel > 0 => Some(key.as_int()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_keeps_the_note_order() {
+        let sheet = parse_sheet("#length 4\na s | [df] g\n\nh j k l z").unwrap();
+        let exported = to_midi(&sheet, &durations(&sheet));
+        let imported = from_midi(&exported).unwrap();
+        let reexported = to_midi(&imported, &durations(&imported));
+
+        let notes = note_ons(&exported);
+        assert_eq!(notes.len(), 10);
+        assert_eq!(note_ons(&reexported), notes);
+    }
+}
@@ -0,0 +1,63 @@
+use enigo::Key;
+
+use super::Token;
+
+/// Notes starting within this many seconds of each other are played as one chord.
+const CHORD_WINDOW: f64 = 0.03;
+
+fn gap_token(gap: f64, base: f64) -> Option<Token> {
+    let ratio = gap / base;
+    if ratio < 1.5 {
+        None
+    } else if ratio < 2.5 {
+        Some(Token::ShortPause)
+    } else if ratio < 4.0 {
+        Some(Token::Pause)
+    } else {
+        Some(Token::LongPause)
+    }
+}
+
+/// Turns timed key presses, sorted by time in seconds, into tokens. Presses close together become
+/// chords, and gaps are turned into pauses by how long they are compared to the typical (median)
+/// time between notes.
+pub(crate) fn tokens_from_onsets(onsets: &[(f64, Key)]) -> Vec<Token> {
+    let mut groups: Vec<(f64, Vec<Key>)> = Vec::new();
+    for &(time, key) in onsets {
+        match groups.last_mut() {
+            Some((start, keys)) if time - *start <= CHORD_WINDOW => {
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+            _ => groups.push((time, vec![key])),
+        }
+    }
+
+    let mut gaps: Vec<f64> = groups
+        .windows(2)
+        .map(|pair| pair[1].0 - pair[0].0)
+        .filter(|&gap| gap > 0.0)
+        .collect();
+    gaps.sort_by(f64::total_cmp);
+    let base = gaps.get(gaps.len() / 2).copied().unwrap_or(1.0);
+
+    let mut tokens = Vec::new();
+    let mut previous_start = None;
+    for (start, mut keys) in groups {
+        if let Some(previous) = previous_start
+            && let Some(pause) = gap_token(start - previous, base)
+        {
+            tokens.push(pause);
+        }
+        previous_start = Some(start);
+
+        if keys.len() == 1 {
+            tokens.push(Token::Single(keys.remove(0), None));
+        } else {
+            tokens.push(Token::Many(keys.into(), None));
+        }
+    }
+
+    tokens
+}
@@ -0,0 +1,254 @@
+use std::fmt::Write;
+
+use enigo::Key;
+
+use super::{Sheet, Token};
+
+/// Characters that are sheet syntax somewhere, so a key with one of them is always escaped.
+const SYNTAX: &[char] = &[
+    '[', ']', '|', '<', '>', '{', '}', '*', ':', '$', '\\', '/', '#', '@', '~', '_', '.', ' ', '\t',
+];
+
+/// Defines that [`to_string`] writes itself rather than copying from the header's metadata.
+const WRITTEN_DEFINES: &[&str] = &["title", "writer", "bpm", "beats", "length", "spaces"];
+
+/// Writes a key, escaping it if the parser would otherwise read it as syntax. `after_factor` is
+/// set right after a `:N` factor or `.N` rest, where a digit would be taken as part of the number.
+fn write_key(output: &mut String, key: &Key, after_factor: bool) {
+    // Only character keys can appear in a sheet.
+    let Key::Unicode(character) = *key else {
+        return;
+    };
+
+    let continues_factor = after_factor && character.is_ascii_digit();
+    if SYNTAX.contains(&character) || continues_factor {
+        output.push('\\');
+    }
+    output.push(character);
+}
+
+/// Writes a `:N` factor, returning whether one was written and whether it has a `.` in it.
+fn write_factor(output: &mut String, length: Option<f64>) -> (bool, bool) {
+    match length {
+        Some(length) => {
+            let factor = length.to_string();
+            _ = write!(output, ":{}", factor);
+            (true, factor.contains('.'))
+        }
+        None => (false, false),
+    }
+}
+
+/// Writes `sheet` back out in the text format that [`parse_sheet`](super::parse_sheet) reads.
+///
+/// Repeats and octave shifts are already expanded in the tokens, so they come out as plain keys.
+/// A long pause is written as a blank line, or as `||` where a blank line would run into the one
+/// before it, since any number of blank lines is a single long pause.
+pub fn to_string(sheet: &Sheet) -> String {
+    let mut output = String::new();
+
+    let header = &sheet.header;
+    if let Some(title) = &header.title {
+        _ = writeln!(output, "#title {}", title);
+    }
+    if let Some(writer) = &header.writer {
+        _ = writeln!(output, "#writer {}", writer);
+    }
+    match header.bpm {
+        Some(bpm) => {
+            _ = writeln!(output, "#bpm {}", bpm);
+            let beats = header.length * bpm / 60.0;
+            if beats > 0.0 {
+                _ = writeln!(output, "#beats {}", beats);
+            }
+        }
+        None => {
+            let minutes = (header.length / 60.0).floor();
+            let seconds = header.length - minutes * 60.0;
+            // Seconds take two digits before any fraction, as in `1:05` or `1:05.5`.
+            let padding = if seconds < 10.0 { "0" } else { "" };
+            _ = writeln!(output, "#length {}:{}{}", minutes, padding, seconds);
+        }
+    }
+
+    // The defines above are written from the typed fields, which may have been changed since.
+    let mut metadata: Vec<_> = header
+        .metadata
+        .iter()
+        .filter(|(name, _)| !WRITTEN_DEFINES.contains(&name.as_str()))
+        .collect();
+    metadata.sort();
+    for (name, value) in metadata {
+        _ = writeln!(output, "#{} {}", name, value);
+    }
+
+    // Runs of spaces only come from scaled sheets, so writing them back needs the same mode. Other
+    // adjacent short pauses then go on separate lines, so they are not read back as one run.
+    let scaled = sheet
+        .tokens
+        .iter()
+        .any(|token| matches!(token, Token::ShortPauses(_)));
+    if scaled {
+        output.push_str("#spaces scaled\n");
+    }
+
+    let mut after_factor = false;
+    let mut factor_has_dot = false;
+    let mut after_single = false;
+    let mut after_spaces = false;
+    let mut line_empty = true;
+    for token in &sheet.tokens {
+        let was_after_factor = after_factor;
+        let was_after_single = after_single;
+        let was_after_spaces = after_spaces;
+        let was_line_empty = line_empty;
+        after_factor = false;
+        after_single = false;
+        after_spaces = matches!(token, Token::ShortPause | Token::ShortPauses(_));
+        line_empty = false;
+        if scaled && was_after_spaces && after_spaces {
+            output.push('\n');
+        }
+        match token {
+            Token::ShortPause => output.push(' '),
+            Token::ShortPauses(spaces) => output.push_str(&" ".repeat(*spaces)),
+            Token::Pause => {
+                // Next to another `|` it would be read as `||`, a long pause.
+                if output.ends_with('|') {
+                    output.push('\n');
+                }
+                output.push('|');
+            }
+            Token::LongPause if was_line_empty => output.push_str("||"),
+            Token::LongPause => {
+                output.push_str("\n\n");
+                line_empty = true;
+            }
+            Token::Rest(notes) => {
+                // A factor takes a single `.`, so one without a `.` gets it first to end the
+                // number: `a:2..5`.
+                if was_after_factor && !factor_has_dot {
+                    output.push('.');
+                }
+                let notes = notes.to_string();
+                _ = write!(output, ".{}", notes);
+                (after_factor, factor_has_dot) = (true, notes.contains('.'));
+            }
+            Token::Single(key, length) => {
+                write_key(&mut output, key, was_after_factor);
+                (after_factor, factor_has_dot) = write_factor(&mut output, *length);
+                after_single = !after_factor;
+            }
+            Token::Many(keys, length) => {
+                output.push('[');
+                for key in keys {
+                    write_key(&mut output, key, false);
+                }
+                output.push(']');
+                (after_factor, factor_has_dot) = write_factor(&mut output, *length);
+            }
+            Token::ManyFast(keys) => {
+                output.push('[');
+                for (index, key) in keys.iter().enumerate() {
+                    if index == 1 {
+                        output.push(' ');
+                    }
+                    write_key(&mut output, key, false);
+                }
+                if keys.len() < 2 {
+                    output.push(' ');
+                }
+                output.push(']');
+            }
+            Token::Tied(key, length) => {
+                write_key(&mut output, key, was_after_factor);
+                _ = write_factor(&mut output, *length);
+                output.push('_');
+            }
+            Token::Hold(key) => {
+                write_key(&mut output, key, was_after_factor);
+                output.push('~');
+            }
+            Token::ReleaseHold(key) => {
+                // Straight after a note, `~` would hold that note instead. A line break ends the
+                // note first and takes no time.
+                if was_after_single {
+                    output.push('\n');
+                }
+                output.push('~');
+                write_key(&mut output, key, false);
+            }
+            Token::TempoStart(target) => _ = write!(output, "{{rit {}}}", target),
+            Token::TempoEnd => output.push_str("{end}"),
+        }
+    }
+    if !line_empty {
+        output.push('\n');
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng, seq::IndexedRandom};
+
+    use super::*;
+    use crate::parse_sheet;
+
+    /// Pieces of sheet text, joined at random into sheets for the round trip.
+    const FRAGMENTS: &[&str] = &[
+        "a", "s", "D", "@", "1", " ", "  ", "|", "\n", "\n\n", "[df]", "[g h]", "[k ]", "j~", "~j",
+        "l_", "l", "q:2", "w:1.5", ".5", "e.2", "\\.", "\\*", "\\:", "{rit 2}", "{end}", "[zx]:3",
+    ];
+
+    fn random_sheet(rng: &mut StdRng) -> String {
+        let lengths = ["30", "61", "75.5", "3600.25", "9.5", "1:05"];
+        let mut sheet = format!("#title Random\n#length {}\n", lengths.choose(rng).unwrap());
+        if rng.random_bool(0.3) {
+            sheet.push_str("#spaces scaled\n");
+        }
+        for _ in 0..rng.random_range(1..40) {
+            sheet.push_str(FRAGMENTS.choose(rng).unwrap());
+        }
+        sheet
+    }
+
+    #[test]
+    fn length_pads_the_seconds() {
+        for (length, written) in [("65", "1:05"), ("125.5", "2:05.5"), ("600", "10:00")] {
+            let sheet = parse_sheet(&format!("#length {}\na", length)).unwrap();
+            assert!(
+                to_string(&sheet).contains(&format!("#length {}\n", written)),
+                "{}",
+                to_string(&sheet)
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_the_sheet() {
+        let mut rng = StdRng::seed_from_u64(36);
+        let mut parsed = 0;
+        for _ in 0..2000 {
+            let input = random_sheet(&mut rng);
+            // Unbalanced ties, holds and tempo spans are errors, and there is nothing to write.
+            let Ok(sheet) = parse_sheet(&input) else {
+                continue;
+            };
+            parsed += 1;
+
+            let written = to_string(&sheet);
+            let reparsed = parse_sheet(&written)
+                .unwrap_or_else(|err| panic!("{:?} was written as {:?}: {}", input, written, err));
+            assert_eq!(
+                reparsed.tokens, sheet.tokens,
+                "{:?} as {:?}",
+                input, written
+            );
+            assert_eq!(reparsed.header.title, sheet.header.title);
+            assert_eq!(reparsed.header.length, sheet.header.length);
+        }
+        assert!(parsed > 300, "only {} random sheets parsed", parsed);
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use enigo::Key;
+
+use super::{Chord, Header, Position, Sheet, SheetError, Token, count_notes};
+use crate::layout;
+
+/// Community sheets don't give a length, so imported ones play at about four notes a second.
+const SECONDS_PER_NOTE: f64 = 0.25;
+
+fn character(key: &Key) -> Option<char> {
+    match *key {
+        Key::Unicode(character) => Some(character),
+        _ => None,
+    }
+}
+
+/// The notes of `sheet` in the notation shared on virtualpiano.net, ready to paste into the site.
+///
+/// Their sheets only have keys, chords (`[asd]`), fast arpeggios (`[a s d]`), spaces for short
+/// pauses, `|` for pauses and blank lines between phrases, so the rest is approximated: a note
+/// held for N beats is followed by N - 1 spaces, as is a tie, and a rest of N notes is N spaces.
+/// Sustains are played as plain notes, and tempo spans and the header are left out.
+pub fn to_virtualpiano_string(sheet: &Sheet) -> String {
+    let mut output = String::new();
+    let spaces = |output: &mut String, beats: f64| {
+        output.push_str(&" ".repeat(beats.round().max(0.0) as usize));
+    };
+
+    let mut tied: Option<Key> = None;
+    for token in &sheet.tokens {
+        match token {
+            Token::ShortPause => output.push(' '),
+            Token::ShortPauses(count) => output.push_str(&" ".repeat(*count)),
+            Token::Pause => output.push('|'),
+            Token::LongPause => {
+                // Trailing spaces before a break are noise on the site.
+                output.truncate(output.trim_end_matches(' ').len());
+                output.push_str("\n\n");
+            }
+            Token::Rest(notes) => spaces(&mut output, notes.max(1.0)),
+            Token::Single(key, length) | Token::Tied(key, length) => {
+                let length = length.unwrap_or(1.0);
+                match tied == Some(*key) {
+                    // The key is already down, so the tied note only adds time.
+                    true => spaces(&mut output, length),
+                    false => {
+                        output.extend(character(key));
+                        spaces(&mut output, length - 1.0);
+                    }
+                }
+                tied = match token {
+                    Token::Tied(..) => Some(*key),
+                    _ => None,
+                };
+            }
+            Token::Many(keys, length) => {
+                output.push('[');
+                output.extend(keys.iter().filter_map(character));
+                output.push(']');
+                spaces(&mut output, length.unwrap_or(1.0) - 1.0);
+            }
+            Token::ManyFast(keys) => {
+                let keys: Vec<String> = keys
+                    .iter()
+                    .filter_map(character)
+                    .map(String::from)
+                    .collect();
+                output.push('[');
+                output.push_str(&keys.join(" "));
+                output.push(']');
+            }
+            Token::Hold(key) => output.extend(character(key)),
+            Token::ReleaseHold(_) | Token::TempoStart(_) | Token::TempoEnd => {}
+        }
+    }
+
+    output.trim_end().to_string()
+}
+
+/// Reads a sheet written in the notation shared on virtualpiano.net, where every character on the
+/// piano is a key, including the shifted ones like `@` and `$` that are syntax in the text format.
+///
+/// `[asd]` is a chord and `[a s d]` a fast arpeggio, a space is a short pause and `|` a pause.
+/// A line break is a short pause too, and a blank line a long pause. Anything that isn't
+/// a key, like `-` or a typographic quote, is skipped. The length is set from the number of notes.
+pub fn from_virtualpiano(input: &str) -> Result<Sheet, SheetError> {
+    let mut tokens = Vec::new();
+    let mut group: Option<(Chord, bool, Position)> = None;
+    let mut blank_lines = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.trim().is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+        if !tokens.is_empty() {
+            tokens.push(match blank_lines {
+                0 => Token::ShortPause,
+                _ => Token::LongPause,
+            });
+        }
+        blank_lines = 0;
+
+        for (column, character) in line.chars().enumerate() {
+            let position = Position {
+                line: index + 1,
+                column: column + 1,
+            };
+            match (character, &mut group) {
+                ('[', None) => group = Some((Chord::new(), false, position)),
+                ('[', Some(_)) => return Err(SheetError::UnmatchedBracket(position)),
+                (']', None) => return Err(SheetError::CloseWithoutOpen(position)),
+                (']', Some(_)) => {
+                    let (keys, fast, _) = group.take().unwrap();
+                    match (fast, keys.len()) {
+                        (_, 0) => {}
+                        (true, _) => tokens.push(Token::ManyFast(keys)),
+                        (false, _) => tokens.push(Token::Many(keys, None)),
+                    }
+                }
+                (' ' | '\t', Some((_, fast, _))) => *fast = true,
+                (' ' | '\t', None) => tokens.push(Token::ShortPause),
+                ('|', None) => tokens.push(Token::Pause),
+                (key, Some((keys, _, _))) if layout::index_of(key).is_some() => {
+                    keys.push(Key::Unicode(key))
+                }
+                (key, None) if layout::index_of(key).is_some() => {
+                    tokens.push(Token::Single(Key::Unicode(key), None))
+                }
+                _ => {}
+            }
+        }
+        if let Some((_, _, start)) = group {
+            return Err(SheetError::UnmatchedBracket(start));
+        }
+    }
+
+    Ok(Sheet {
+        header: Header {
+            title: None,
+            writer: None,
+            length: count_notes(&tokens) as f64 * SECONDS_PER_NOTE,
+            bpm: None,
+            metadata: HashMap::new(),
+        },
+        tokens,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::parse_sheet;
+
+    #[test]
+    fn round_trip_through_their_notation() {
+        let shared = "[tu] o | p [ad] s\n\n[f h k] g  8 *";
+        let sheet = from_virtualpiano(shared).unwrap();
+        assert_eq!(to_virtualpiano_string(&sheet).trim_end(), shared);
+        assert_eq!(
+            from_virtualpiano(&to_virtualpiano_string(&sheet))
+                .unwrap()
+                .tokens,
+            sheet.tokens
+        );
+    }
+
+    #[test]
+    fn what_their_notation_lacks_is_approximated() {
+        let sheet = parse_sheet("#length 3\na:3 s_s d~ f ~d .2 g{rit 2}h{end}").unwrap();
+        // The held `a` and tied `s` are followed by a space per extra beat, the sustained `d` is
+        // a plain note, and the rest becomes two spaces.
+        assert_eq!(
+            to_virtualpiano_string(&sheet).trim_end(),
+            "a   s  d f     gh"
+        );
+    }
+
+    #[test]
+    fn community_snippets() {
+        let single = |c| Token::Single(Key::Unicode(c), None);
+        let chord = |keys: &str| Token::Many(keys.chars().map(Key::Unicode).collect(), None);
+
+        // Für Elise, with the upper keys typed with shift.
+        let sheet = from_virtualpiano("f D f D f a d s p\n[0et] u o p").unwrap();
+        // Nine notes and a chord with three more, with a pause between each and at the line break.
+        assert_eq!(sheet.tokens.len(), 25);
+        assert_eq!(sheet.tokens[2], single('D'));
+        assert_eq!(sheet.tokens[18], chord("0et"));
+        assert_eq!(sheet.header.length, 13.0 * SECONDS_PER_NOTE);
+
+        // Numbers and symbols are keys, and a line break is a short pause.
+        assert_eq!(
+            from_virtualpiano("[6@]|%\n$").unwrap().tokens,
+            [
+                chord("6@"),
+                Token::Pause,
+                single('%'),
+                Token::ShortPause,
+                single('$')
+            ]
+        );
+
+        // Blank lines split verses, spaced chords are fast arpeggios, and characters that aren't
+        // keys, like the dashes some sheets are decorated with, are left out.
+        assert_eq!(
+            from_virtualpiano("-- [e t u] --\n\n\nwq").unwrap().tokens,
+            [
+                Token::ShortPause,
+                Token::ManyFast("etu".chars().map(Key::Unicode).collect()),
+                Token::ShortPause,
+                Token::LongPause,
+                single('w'),
+                single('q'),
+            ]
+        );
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Instant;
+
+use enigo::{Direction, InputError, Key};
+
+pub use crate::clock::FakeClock;
+use crate::{clock::Clock, output::KeyOutput};
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyEvent {
+    pub at: Instant,
+    pub direction: Direction,
+    pub key: Key,
+}
+
+#[derive(Default)]
+pub struct RecordingKeyboard {
+    events: Vec<KeyEvent>,
+    clock: Option<FakeClock>,
+}
+
+impl RecordingKeyboard {
+    pub fn new() -> RecordingKeyboard {
+        RecordingKeyboard::default()
+    }
+
+    /// A keyboard that timestamps its events with `clock` rather than the real time.
+    pub fn with_clock(clock: FakeClock) -> RecordingKeyboard {
+        RecordingKeyboard {
+            events: Vec::new(),
+            clock: Some(clock),
+        }
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    fn record(&mut self, direction: Direction, key: Key) {
+        let at = match &self.clock {
+            Some(clock) => clock.now(),
+            None => Instant::now(),
+        };
+        self.events.push(KeyEvent { at, direction, key });
+    }
+}
+
+impl KeyOutput for RecordingKeyboard {
+    fn press(&mut self, key: Key) -> Result<(), InputError> {
+        self.record(Direction::Press, key);
+        Ok(())
+    }
+
+    fn release(&mut self, key: Key) -> Result<(), InputError> {
+        self.record(Direction::Release, key);
+        Ok(())
+    }
+}